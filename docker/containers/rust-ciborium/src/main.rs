@@ -1,276 +1,1436 @@
 //! CBOR Test Container - Rust with ciborium library
 //! Implements the standard HTTP bridge protocol
 
-use ciborium::value::Value;
+mod selftest;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use serde_json::{json, Value as JsonValue};
-use std::io::{Read, Write};
-use std::time::Instant;
-use tiny_http::{Header, Method, Response, Server};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::io::{self as stdio, Cursor, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use taco_cbor::{
+    bench_cbor, compare_cbor, decode_cbor, decode_cbor_bytes, decode_cbor_seq, diagnostic_cbor,
+    encode_cbor, encode_cbor_seq, encode_cbor_value, encode_diag, error_response, keys_cbor,
+    roundtrip_cbor, transcode_json, validate_cbor, ArrayStream, CanonicalMode, DecodeOptions,
+    EncodeOptions, ErrorCode, TAG_HANDLERS,
+};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
 
-const PORT: u16 = 8080;
+/// Listen address unless overridden with TACO_BIND and TACO_PORT
+const DEFAULT_BIND: &str = "0.0.0.0";
+const DEFAULT_PORT: u16 = 8080;
 const LIBRARY_NAME: &str = "ciborium";
-const LIBRARY_VERSION: &str = "0.2.2";
+/// The ciborium version in Cargo.lock, set by build.rs so it cannot drift from what is compiled in
+const LIBRARY_VERSION: &str = env!("CIBORIUM_VERSION");
+/// When and from which commit this binary was built (see build.rs)
+const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+const GIT_SHA: &str = env!("GIT_SHA");
 const LANGUAGE: &str = "rust";
 
-/// Convert CBOR Value to JSON-safe format with type markers
-fn cbor_to_json(value: Value) -> JsonValue {
-    match value {
-        Value::Integer(i) => {
-            let num = i128::from(i);
-            // Check if it fits in JavaScript safe integer range
-            if num > 9007199254740991 || num < -9007199254740991 {
-                json!(num.to_string())
-            } else {
-                json!(num)
+/// Optional behaviors this container supports, reported by /info; extend when adding one
+const FEATURES: &[&str] = &[
+    "validate",
+    "roundtrip",
+    "diagnostic",
+    "encode_diag",
+    "decode_seq",
+    "compare",
+    "indefinite",
+    "canonical",
+    "bignum",
+    "undefined",
+    "simple",
+    "strict_maps",
+    "max_safe_int",
+    "float_shrink",
+    "depth_limit",
+    "hash",
+    "base64url",
+    "preserve_int_keys",
+    "datetime",
+    "max_body",
+    "verbose_bytes",
+    "check_minimal",
+    "encode_tag",
+    "negative_zero",
+    "raw_cbor_body",
+    "raw_cbor_response",
+    "strict",
+    "root_tag",
+    "decode_embedded",
+    "metrics",
+    "float_subtype",
+    "decimal_fraction",
+    "bigfloat",
+    "content_type_check",
+    "encode_canonical",
+    "ordered",
+    "panic_guard",
+    "encode_seq",
+    "nan_bits",
+    "request_timeout",
+    "stats",
+    "gzip",
+    "force_half",
+    "trailing_bytes",
+    "request_log",
+    "text_marker",
+    "int_width",
+    "stream",
+    "float_marker_number",
+    "indefinite_strings",
+    "warnings",
+    "canonical_mode",
+    "offsets",
+    "preserve_u64",
+    "bytes_encoding",
+    "expect_major",
+    "path",
+    "timing_breakdown",
+    "text_tags",
+    "bench",
+    "reject_indefinite",
+    "maps_as_pairs",
+    "check_canonical",
+    "int_map",
+    "max_output_bytes",
+    "tag_handlers",
+    "transcode",
+    "empty_body",
+    "limit",
+    "explain",
+    "lenient_json",
+    "keys",
+    "cose",
+    "unix_socket",
+    "float_as_string",
+    "break_terminated",
+];
+
+/// How often each worker checks for a shutdown signal while idle
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Requests handled concurrently, so a slow decode cannot starve /health
+const WORKER_THREADS: usize = 4;
+
+/// Worker stack size, matching the usual main thread stack: decoding and converting an item
+/// nested MAX_DEPTH levels deep recurses well past the 2 MiB default for spawned threads
+const WORKER_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// How long the healthcheck probe waits for the server to answer
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Default request body limit (16 MiB), overridable with TACO_MAX_BODY
+const DEFAULT_MAX_BODY: usize = 16 * 1024 * 1024;
+
+/// Read the request body, or `None` if it is larger than `max_body`
+///
+/// Content-Length is checked up front; without one (chunked uploads) the read is capped
+/// so an oversized body is never buffered in full.
+fn read_body(request: &mut Request, max_body: usize) -> Option<Vec<u8>> {
+    if request.body_length().is_some_and(|len| len > max_body) {
+        return None;
+    }
+
+    let mut body = Vec::new();
+    let limit = max_body as u64 + 1;
+    request
+        .as_reader()
+        .take(limit)
+        .read_to_end(&mut body)
+        .unwrap_or(0);
+
+    (body.len() <= max_body).then_some(body)
+}
+
+/// Whether a request header lists `media_type`, ignoring parameters and case
+///
+/// Handles both single-valued headers like Content-Type and comma-separated lists like Accept.
+fn has_media_type(headers: &[Header], name: &'static str, media_type: &str) -> bool {
+    headers
+        .iter()
+        .filter(|header| header.field.equiv(name))
+        .flat_map(|header| header.value.as_str().split(','))
+        .any(|item| {
+            let essence = item.split(';').next().unwrap_or_default();
+            essence.trim().eq_ignore_ascii_case(media_type)
+        })
+}
+
+/// Whether the declared body type is JSON, or raw CBOR when `allow_cbor`
+///
+/// A request without a Content-Type header is read as JSON, as it always has been.
+fn body_media_type_supported(headers: &[Header], allow_cbor: bool) -> bool {
+    let declared = headers
+        .iter()
+        .any(|header| header.field.equiv("Content-Type"));
+
+    !declared
+        || has_media_type(headers, "Content-Type", "application/json")
+        || (allow_cbor && has_media_type(headers, "Content-Type", "application/cbor"))
+}
+
+/// The error for a request with no body, which would otherwise read as invalid JSON or CBOR
+fn empty_body() -> JsonValue {
+    error_response(ErrorCode::EmptyBody, "request body is empty")
+}
+
+/// Parse a JSON request body, returning an error response on failure
+fn read_json_body(body: &[u8]) -> Result<JsonValue, JsonValue> {
+    if body.trim_ascii().is_empty() {
+        return Err(empty_body());
+    }
+    serde_json::from_slice::<JsonValue>(body)
+        .map_err(|e| error_response(ErrorCode::InvalidJson, format!("Invalid JSON: {}", e)))
+}
+
+/// Rewrite bare `NaN`, `Infinity` and `-Infinity` tokens outside strings to `__cbor_float__` markers
+///
+/// This is the non-standard TACO_LENIENT_JSON mode. Anything that is not one of the three
+/// tokens, including text inside string literals, is copied unchanged for serde_json to judge.
+fn lenient_json(body: Vec<u8>) -> Vec<u8> {
+    const TOKENS: [(&[u8], &[u8]); 3] = [
+        (b"NaN", br#"{"__cbor_float__":"NaN"}"#),
+        (b"Infinity", br#"{"__cbor_float__":"Infinity"}"#),
+        (b"-Infinity", br#"{"__cbor_float__":"-Infinity"}"#),
+    ];
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+
+    let mut out = Vec::with_capacity(body.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut i = 0;
+    while i < body.len() {
+        let b = body[i];
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+        } else if b == b'"' {
+            in_string = true;
+        } else if i == 0 || !is_word(body[i - 1]) {
+            let token = TOKENS.iter().find(|(token, _)| {
+                body[i..].starts_with(token)
+                    && body.get(i + token.len()).is_none_or(|&b| !is_word(b))
+            });
+            if let Some((token, marker)) = token {
+                out.extend_from_slice(marker);
+                i += token.len();
+                continue;
             }
         }
-        Value::Bytes(bytes) => {
-            json!({ "__cbor_bytes__": hex::encode(&bytes) })
+        out.push(b);
+        i += 1;
+    }
+    out
+}
+
+/// Run a handler on the "hex" payload field of a JSON request body, passing the full body for options
+fn with_hex_field(body: &[u8], handler: impl FnOnce(&str, &JsonValue) -> JsonValue) -> JsonValue {
+    match read_json_body(body) {
+        Ok(json) => match json.get("hex").and_then(|v| v.as_str()) {
+            Some(hex) => handler(hex, &json),
+            None => error_response(ErrorCode::MissingField, "Missing \"hex\" field"),
+        },
+        Err(e) => e,
+    }
+}
+
+/// Settings read from the environment at startup
+struct ServerConfig {
+    /// Whether to check the Appendix A examples before serving (TACO_SELFTEST=1)
+    selftest: bool,
+    /// Address to listen on (TACO_BIND)
+    bind: String,
+    /// Port to listen on (TACO_PORT)
+    port: u16,
+    /// Unix domain socket to listen on instead of TCP (TACO_UNIX_SOCKET)
+    ///
+    /// When set, TACO_BIND and TACO_PORT are ignored: the server listens on the socket only,
+    /// and the image's HEALTHCHECK (`cbor-test-server healthcheck`) probes the socket too. A
+    /// stale socket file left at the path is replaced, and the file is removed again on shutdown.
+    unix_socket: Option<PathBuf>,
+    /// Whether POST /shutdown may stop the server (TACO_ALLOW_SHUTDOWN=1)
+    allow_shutdown: bool,
+    /// Largest accepted request body in bytes (TACO_MAX_BODY)
+    max_body: usize,
+    /// Wall-clock budget for routing one request (TACO_REQUEST_TIMEOUT_MS); None or 0 means no limit
+    ///
+    /// When set, each request is routed on its own handler thread while the worker waits for at
+    /// most this long. On expiry the worker answers 504 and takes the next request, but the
+    /// handler thread cannot be interrupted: it runs to completion in the background and its
+    /// response is dropped. A flood of slow inputs can therefore occupy more threads than
+    /// WORKER_THREADS, which still bounds how many requests are accepted concurrently.
+    request_timeout: Option<Duration>,
+    /// Log one JSON line per request to stderr (TACO_LOG=debug)
+    log_requests: bool,
+    /// Accept bare `NaN`, `Infinity` and `-Infinity` in JSON bodies (TACO_LENIENT_JSON=1)
+    ///
+    /// These tokens are not JSON, which has no way to spell non-finite numbers; the lenient mode
+    /// exists for clients (JavaScript's and Python's default serializers among them) that emit
+    /// them anyway. Each token is rewritten to the matching `__cbor_float__` marker before parsing.
+    lenient_json: bool,
+}
+
+impl ServerConfig {
+    fn from_env() -> Self {
+        ServerConfig {
+            selftest: std::env::var("TACO_SELFTEST").is_ok_and(|v| v == "1"),
+            bind: std::env::var("TACO_BIND").unwrap_or_else(|_| DEFAULT_BIND.to_string()),
+            port: std::env::var("TACO_PORT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_PORT),
+            unix_socket: std::env::var_os("TACO_UNIX_SOCKET")
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+            allow_shutdown: std::env::var("TACO_ALLOW_SHUTDOWN").is_ok_and(|v| v == "1"),
+            max_body: std::env::var("TACO_MAX_BODY")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_BODY),
+            request_timeout: std::env::var("TACO_REQUEST_TIMEOUT_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            log_requests: std::env::var("TACO_LOG").is_ok_and(|v| v == "debug"),
+            lenient_json: std::env::var("TACO_LENIENT_JSON").is_ok_and(|v| v == "1"),
+        }
+    }
+}
+
+/// Request counters reported by /metrics, shared by all workers
+#[derive(Default)]
+struct Metrics {
+    requests: AtomicU64,
+    decodes: AtomicU64,
+    encodes: AtomicU64,
+    /// Requests answered with `"success": false` or an error status
+    errors: AtomicU64,
+    /// Cumulative handling time of decode and encode requests, in microseconds
+    decode_micros: AtomicU64,
+    encode_micros: AtomicU64,
+}
+
+impl Metrics {
+    fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an error if a handler's JSON result reports failure
+    fn record_result(&self, result: &JsonValue) {
+        if !is_success(result) {
+            self.record_error();
         }
-        Value::Float(f) => {
-            if f.is_nan() {
-                json!({"__cbor_float__": "NaN"})
-            } else if f.is_infinite() {
-                if f.is_sign_positive() {
-                    json!({"__cbor_float__": "Infinity"})
-                } else {
-                    json!({"__cbor_float__": "-Infinity"})
+    }
+
+    fn record_decode(&self, succeeded: bool, started: Instant) {
+        self.decodes.fetch_add(1, Ordering::Relaxed);
+        self.decode_micros
+            .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        if !succeeded {
+            self.record_error();
+        }
+    }
+
+    fn record_encode(&self, succeeded: bool, started: Instant) {
+        self.encodes.fetch_add(1, Ordering::Relaxed);
+        self.encode_micros
+            .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        if !succeeded {
+            self.record_error();
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        json!({
+            "requests": self.requests.load(Ordering::Relaxed),
+            "decodes": self.decodes.load(Ordering::Relaxed),
+            "encodes": self.encodes.load(Ordering::Relaxed),
+            "errors": self.errors.load(Ordering::Relaxed),
+            "decode_time_ms": self.decode_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+            "encode_time_ms": self.encode_micros.load(Ordering::Relaxed) as f64 / 1000.0
+        })
+    }
+
+    /// Render the counters in the Prometheus text exposition format
+    fn to_prometheus(&self) -> String {
+        let counters = [
+            (
+                "requests_total",
+                "Requests received",
+                self.requests.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "decodes_total",
+                "Decode requests handled",
+                self.decodes.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "encodes_total",
+                "Encode requests handled",
+                self.encodes.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "errors_total",
+                "Requests that failed",
+                self.errors.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "decode_seconds_total",
+                "Time spent handling decode requests",
+                self.decode_micros.load(Ordering::Relaxed) as f64 / 1e6,
+            ),
+            (
+                "encode_seconds_total",
+                "Time spent handling encode requests",
+                self.encode_micros.load(Ordering::Relaxed) as f64 / 1e6,
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, help, value) in counters {
+            out.push_str(&format!("# HELP taco_{} {}\n", name, help));
+            out.push_str(&format!("# TYPE taco_{} counter\n", name));
+            out.push_str(&format!("taco_{} {}\n", name, value));
+        }
+        out
+    }
+}
+
+/// Whether a handler's JSON result reports success
+fn is_success(result: &JsonValue) -> bool {
+    result["success"] != false
+}
+
+/// Everything the workers share besides the server itself
+struct AppState {
+    config: ServerConfig,
+    metrics: Metrics,
+    /// Raised by SIGTERM/SIGINT or /shutdown
+    shutdown: Arc<AtomicBool>,
+    /// Source of the ids sent back as X-Request-Id and written to the request log
+    next_request_id: AtomicU64,
+}
+
+fn main() {
+    let config = ServerConfig::from_env();
+
+    // The image's HEALTHCHECK runs the binary itself so the probe follows the same settings
+    if std::env::args().nth(1).as_deref() == Some("healthcheck") {
+        std::process::exit(if healthcheck(&config) { 0 } else { 1 });
+    }
+
+    // Refuse to serve from a build that gets the RFC examples wrong
+    if config.selftest {
+        match selftest::run() {
+            Ok(count) => eprintln!("Self-test passed: {} vectors", count),
+            Err(failures) => {
+                eprintln!("Self-test failed for {} vectors:", failures.len());
+                for failure in failures {
+                    eprintln!("  {}", failure);
                 }
-            } else {
-                json!(f)
+                std::process::exit(1);
             }
         }
-        Value::Text(s) => json!(s),
-        Value::Bool(b) => json!(b),
-        Value::Null => json!(null),
-        Value::Array(arr) => {
-            let converted: Vec<JsonValue> = arr.into_iter().map(cbor_to_json).collect();
-            json!(converted)
-        }
-        Value::Map(map) => {
-            let mut result = serde_json::Map::new();
-            for (k, v) in map {
-                let key = match k {
-                    Value::Text(s) => s,
-                    Value::Integer(i) => i128::from(i).to_string(),
-                    Value::Bytes(b) => hex::encode(&b),
-                    _ => format!("{:?}", k),
-                };
-                result.insert(key, cbor_to_json(v));
-            }
-            json!(result)
+    }
+
+    let server = match &config.unix_socket {
+        Some(path) => {
+            remove_stale_socket(path);
+            Server::http_unix(path)
         }
-        Value::Tag(tag, inner) => {
-            json!({
-                "__cbor_tag__": tag,
-                "__cbor_value__": cbor_to_json(*inner)
+        None => Server::http(format!("{}:{}", config.bind, config.port)),
+    };
+    let server = Arc::new(server.expect("Failed to start server"));
+
+    // Report the address actually bound, which differs from the request for port 0
+    let listening = match &config.unix_socket {
+        Some(path) => path.display().to_string(),
+        None => server.server_addr().to_string(),
+    };
+    println!(
+        "CBOR test container ({} {}) listening on {}",
+        LIBRARY_NAME, LIBRARY_VERSION, listening
+    );
+
+    // SIGTERM/SIGINT and /shutdown all just raise this flag; the workers drain and main exits 0
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))
+            .expect("Failed to register signal handler");
+    }
+    let state = Arc::new(AppState {
+        config,
+        metrics: Metrics::default(),
+        shutdown,
+        next_request_id: AtomicU64::new(1),
+    });
+
+    for worker in spawn_workers(&server, &state) {
+        let _ = worker.join();
+    }
+    if let Some(path) = &state.config.unix_socket {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Whether GET /health answers 200 on the socket or address the server listens on
+///
+/// A wildcard TACO_BIND is probed on loopback, any other address as it is.
+fn healthcheck(config: &ServerConfig) -> bool {
+    fn probe(mut stream: impl Read + Write) -> stdio::Result<Vec<u8>> {
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        Ok(response)
+    }
+
+    let response = match &config.unix_socket {
+        Some(path) => UnixStream::connect(path).and_then(|stream| {
+            stream.set_read_timeout(Some(HEALTHCHECK_TIMEOUT))?;
+            probe(stream)
+        }),
+        None => {
+            let host = match config.bind.as_str() {
+                "0.0.0.0" => "127.0.0.1",
+                "::" => "::1",
+                bind => bind,
+            };
+            TcpStream::connect((host, config.port)).and_then(|stream| {
+                stream.set_read_timeout(Some(HEALTHCHECK_TIMEOUT))?;
+                probe(stream)
             })
         }
-        _ => json!(null),
+    };
+    // The status code follows "HTTP/1.1 "
+    response.is_ok_and(|response| response.get(9..12) == Some(b"200"))
+}
+
+/// Remove a socket file left behind by a server that did not shut down cleanly
+///
+/// Anything else at the path is left alone, so binding then fails instead of deleting a file.
+fn remove_stale_socket(path: &Path) {
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        let _ = std::fs::remove_file(path);
     }
 }
 
-/// Convert JSON value to CBOR Value
-fn json_to_cbor(value: JsonValue) -> Value {
-    match value {
-        JsonValue::Null => Value::Null,
-        JsonValue::Bool(b) => Value::Bool(b),
-        JsonValue::Number(n) => {
-            if let Some(i) = n.as_i64() {
-                Value::Integer(i.into())
-            } else if let Some(f) = n.as_f64() {
-                Value::Float(f)
+/// Start WORKER_THREADS workers serving requests from `server` until shutdown is requested
+fn spawn_workers(server: &Arc<Server>, state: &Arc<AppState>) -> Vec<thread::JoinHandle<()>> {
+    (0..WORKER_THREADS)
+        .map(|_| {
+            let server = Arc::clone(server);
+            let state = Arc::clone(state);
+            thread::Builder::new()
+                .stack_size(WORKER_STACK_SIZE)
+                .spawn(move || serve(&server, &state))
+                .expect("Failed to spawn worker thread")
+        })
+        .collect()
+}
+
+/// Worker loop: take requests off the shared server until shutdown is requested
+fn serve(server: &Server, state: &Arc<AppState>) {
+    while !state.shutdown.load(Ordering::Relaxed) {
+        // Wake up periodically so a signal is noticed even when no requests arrive
+        match server.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+            Ok(Some(request)) => handle_request(request, state),
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Failed to receive request: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Method, URL and headers of a request, owned so routing can move to a handler thread
+struct RequestHead {
+    method: Method,
+    url: String,
+    headers: Vec<Header>,
+}
+
+impl RequestHead {
+    fn of(request: &Request) -> Self {
+        RequestHead {
+            method: request.method().clone(),
+            url: request.url().to_string(),
+            headers: request.headers().to_vec(),
+        }
+    }
+}
+
+/// Route a single request and send its response
+fn handle_request(mut request: Request, state: &Arc<AppState>) {
+    let started = Instant::now();
+    let metrics = &state.metrics;
+    metrics.record_request();
+
+    let request_id = state.next_request_id.fetch_add(1, Ordering::Relaxed);
+
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    let id_header = Header::from_bytes("X-Request-Id", request_id.to_string()).unwrap();
+    // Accept-Encoding is a comma-separated token list, which has_media_type already parses
+    let gzip = has_media_type(request.headers(), "Accept-Encoding", "gzip");
+
+    let body = read_body(&mut request, state.config.max_body).map(|body| {
+        if state.config.lenient_json
+            && !has_media_type(request.headers(), "Content-Type", "application/cbor")
+        {
+            lenient_json(body)
+        } else {
+            body
+        }
+    });
+    let head = RequestHead::of(&request);
+    let response = match body {
+        Some(body) if is_stream_decode(&head) => {
+            let stream = decode_source(&head, &body)
+                .and_then(|(bytes, options)| ArrayStream::start(bytes, options));
+            // Only the setup is timed, since the items are decoded while the response is written
+            metrics.record_decode(stream.is_ok(), started);
+            match stream {
+                Ok(stream) => {
+                    // No gzip and no request timeout here: both need the whole body up front
+                    let headers = vec![content_type, id_header];
+                    let response = Response::new(StatusCode(200), headers, stream, None, None);
+                    if state.config.log_requests {
+                        log_request(request_id, &request, 200, started);
+                    }
+                    let _ = request.respond(response);
+                    return;
+                }
+                Err(result) => Response::from_string(result.to_string()).with_header(content_type),
+            }
+        }
+        Some(body) => match state.config.request_timeout {
+            Some(timeout) => route_with_timeout(head, body, state, started, timeout),
+            None => route_guarded(&head, &body, state, started),
+        },
+        None => {
+            metrics.record_error();
+            let body = error_response(ErrorCode::PayloadTooLarge, "payload too large");
+            Response::from_string(body.to_string())
+                .with_header(content_type)
+                .with_status_code(413)
+        }
+    };
+
+    let status = response.status_code().0;
+    let response = compress_response(response, gzip).with_header(id_header);
+    if state.config.log_requests {
+        log_request(request_id, &request, status, started);
+    }
+    let _ = request.respond(response);
+}
+
+/// Whether a request asks for `/decode?stream=1` with a body /decode accepts
+///
+/// Anything else, including a stream request with an unsupported Content-Type, is routed as usual.
+fn is_stream_decode(request: &RequestHead) -> bool {
+    let (path, query) = request.url.split_once('?').unwrap_or((&request.url, ""));
+    request.method == Method::Post
+        && path == "/decode"
+        && query.split('&').any(|param| param == "stream=1")
+        && body_media_type_supported(&request.headers, true)
+}
+
+/// The payload bytes and options of a /decode body, either raw CBOR or JSON with a "hex" field
+fn decode_source(
+    request: &RequestHead,
+    body: &[u8],
+) -> Result<(Vec<u8>, DecodeOptions), JsonValue> {
+    if has_media_type(&request.headers, "Content-Type", "application/cbor") {
+        if body.is_empty() {
+            return Err(empty_body());
+        }
+        return Ok((body.to_vec(), DecodeOptions::default()));
+    }
+    let json = read_json_body(body)?;
+    let hex = json
+        .get("hex")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| error_response(ErrorCode::MissingField, "Missing \"hex\" field"))?;
+    let options = DecodeOptions::from_request(&json)?;
+    Ok((options.encoding.decode(hex)?, options))
+}
+
+/// Write one JSON line describing a handled request to stderr; stdout only carries the banner
+fn log_request(request_id: u64, request: &Request, status: u16, started: Instant) {
+    let line = json!({
+        "request_id": request_id,
+        "method": request.method().as_str(),
+        "url": request.url(),
+        "status": status,
+        "duration_ms": started.elapsed().as_secs_f64() * 1000.0
+    });
+    eprintln!("{}", line);
+}
+
+/// Gzip a response body when the client accepts it, keeping the status and headers
+fn compress_response(response: Response<Cursor<Vec<u8>>>, gzip: bool) -> Response<Cursor<Vec<u8>>> {
+    if !gzip {
+        return response;
+    }
+    let status = response.status_code();
+    let headers = response.headers().to_vec();
+    let body = response.into_reader().into_inner();
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let (data, encoded) = match encoder.write_all(&body).and_then(|_| encoder.finish()) {
+        Ok(compressed) => (compressed, true),
+        // Compressing into memory only fails on allocation, so just send the body as it was
+        Err(_) => (body, false),
+    };
+
+    let mut compressed = Response::from_data(data).with_status_code(status);
+    for header in headers {
+        compressed.add_header(header);
+    }
+    if encoded {
+        compressed.add_header(Header::from_bytes("Content-Encoding", "gzip").unwrap());
+    }
+    compressed.with_header(Header::from_bytes("Vary", "Accept-Encoding").unwrap())
+}
+
+/// Route on a separate handler thread, answering 504 if it takes longer than `timeout`
+///
+/// See ServerConfig::request_timeout for what happens to a handler that overruns.
+fn route_with_timeout(
+    head: RequestHead,
+    body: Vec<u8>,
+    state: &Arc<AppState>,
+    started: Instant,
+    timeout: Duration,
+) -> Response<Cursor<Vec<u8>>> {
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    let (sender, receiver) = mpsc::channel();
+    let handler_state = Arc::clone(state);
+    let spawned = thread::Builder::new()
+        .stack_size(WORKER_STACK_SIZE)
+        .spawn(move || {
+            // The worker may have stopped listening already, in which case the response is dropped
+            let _ = sender.send(route_guarded(&head, &body, &handler_state, started));
+        });
+    if let Err(e) = spawned {
+        state.metrics.record_error();
+        let body = error_response(ErrorCode::Panic, format!("cannot spawn handler: {}", e));
+        return Response::from_string(body.to_string())
+            .with_header(content_type)
+            .with_status_code(500);
+    }
+
+    match receiver.recv_timeout(timeout) {
+        Ok(response) => response,
+        Err(RecvTimeoutError::Timeout) => {
+            state.metrics.record_error();
+            let message = format!("request exceeded {} ms", timeout.as_millis());
+            let body = error_response(ErrorCode::Timeout, message);
+            Response::from_string(body.to_string())
+                .with_header(content_type)
+                .with_status_code(504)
+        }
+        // route_guarded catches panics, so the handler only disconnects if it dies some other way
+        Err(RecvTimeoutError::Disconnected) => {
+            state.metrics.record_error();
+            let body = error_response(ErrorCode::Panic, "handler thread exited without a response");
+            Response::from_string(body.to_string())
+                .with_header(content_type)
+                .with_status_code(500)
+        }
+    }
+}
+
+/// Route a request, turning a panic in any handler into a 500 instead of taking the thread down
+fn route_guarded(
+    request: &RequestHead,
+    body: &[u8],
+    state: &AppState,
+    started: Instant,
+) -> Response<Cursor<Vec<u8>>> {
+    guard_panics(&state.metrics, || route(request, body, state, started))
+}
+
+/// Run a request handler, answering 500 with the panic message if it panics
+fn guard_panics(
+    metrics: &Metrics,
+    handler: impl FnOnce() -> Response<Cursor<Vec<u8>>>,
+) -> Response<Cursor<Vec<u8>>> {
+    panic::catch_unwind(AssertUnwindSafe(handler)).unwrap_or_else(|payload| {
+        metrics.record_error();
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        let body = error_response(ErrorCode::Panic, format!("internal panic: {}", message));
+        let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+        Response::from_string(body.to_string())
+            .with_header(content_type)
+            .with_status_code(500)
+    })
+}
+
+/// Dispatch a request whose body has been read to its endpoint and build the response
+fn route(
+    request: &RequestHead,
+    body: &[u8],
+    state: &AppState,
+    started: Instant,
+) -> Response<Cursor<Vec<u8>>> {
+    let metrics = &state.metrics;
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+
+    let url = &request.url;
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+
+    match (&request.method, path) {
+        // Health check
+        (&Method::Get, "/health") => {
+            let body = json!({
+                "status": "ok",
+                "library": LIBRARY_NAME,
+                "version": LIBRARY_VERSION,
+                "language": LANGUAGE,
+                "build_timestamp": BUILD_TIMESTAMP,
+                "git_sha": GIT_SHA
+            });
+            Response::from_string(body.to_string()).with_header(content_type)
+        }
+
+        // Supported features
+        (&Method::Get, "/info") => {
+            let body = json!({
+                "library": LIBRARY_NAME,
+                "version": LIBRARY_VERSION,
+                "language": LANGUAGE,
+                "features": FEATURES,
+                "tag_handlers": TAG_HANDLERS.iter().map(|handler| handler.name).collect::<Vec<_>>(),
+                "canonical_modes": CanonicalMode::DESCRIPTIONS
+                    .iter()
+                    .map(|(mode, about)| (mode.to_string(), json!(about)))
+                    .collect::<serde_json::Map<_, _>>()
+            });
+            Response::from_string(body.to_string()).with_header(content_type)
+        }
+
+        // Request counters, as JSON or with ?format=prometheus as Prometheus text
+        (&Method::Get, "/metrics") => {
+            if query.split('&').any(|param| param == "format=prometheus") {
+                let text_type =
+                    Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+                Response::from_string(metrics.to_prometheus()).with_header(text_type)
             } else {
-                Value::Null
+                Response::from_string(metrics.to_json().to_string()).with_header(content_type)
             }
         }
-        JsonValue::String(s) => {
-            // Check if it's a large integer string
-            if let Ok(i) = s.parse::<i128>() {
-                if i <= i64::MAX as i128 && i >= i64::MIN as i128 {
-                    Value::Integer((i as i64).into())
+
+        // Reject bodies these endpoints cannot read (raw CBOR is only understood by /decode)
+        (&Method::Post, "/decode" | "/encode" | "/encode-canonical" | "/encode-seq")
+            if !body_media_type_supported(&request.headers, path == "/decode") =>
+        {
+            metrics.record_error();
+            let body = error_response(ErrorCode::UnsupportedMediaType, "unsupported media type");
+            Response::from_string(body.to_string())
+                .with_header(content_type)
+                .with_status_code(415)
+        }
+
+        // Decode endpoint
+        (&Method::Post, "/decode") => {
+            // A raw CBOR body skips the JSON and hex layers; options then take their defaults
+            let result = if has_media_type(&request.headers, "Content-Type", "application/cbor") {
+                if body.is_empty() {
+                    empty_body()
                 } else {
-                    // For very large integers, keep as text
-                    Value::Text(s)
+                    decode_cbor_bytes(body, &DecodeOptions::default())
                 }
             } else {
-                Value::Text(s)
-            }
+                with_hex_field(body, |hex, json| match DecodeOptions::from_request(json) {
+                    Ok(options) => decode_cbor(hex, &options),
+                    Err(e) => e,
+                })
+            };
+            metrics.record_decode(is_success(&result), started);
+            Response::from_string(result.to_string()).with_header(content_type)
         }
-        JsonValue::Array(arr) => {
-            let converted: Vec<Value> = arr.into_iter().map(json_to_cbor).collect();
-            Value::Array(converted)
+
+        // CBOR sequence decode endpoint
+        (&Method::Post, "/decode-seq") => {
+            let result =
+                with_hex_field(body, |hex, json| match DecodeOptions::from_request(json) {
+                    Ok(options) => decode_cbor_seq(hex, &options),
+                    Err(e) => e,
+                });
+            metrics.record_decode(is_success(&result), started);
+            Response::from_string(result.to_string()).with_header(content_type)
+        }
+
+        // Semantic comparison endpoint
+        (&Method::Post, "/compare") => {
+            let result = match read_json_body(body) {
+                Ok(json) => match (
+                    json.get("a").and_then(|v| v.as_str()),
+                    json.get("b").and_then(|v| v.as_str()),
+                ) {
+                    (Some(a), Some(b)) => compare_cbor(a, b),
+                    _ => error_response(ErrorCode::MissingField, "Missing \"a\" or \"b\" field"),
+                },
+                Err(e) => e,
+            };
+            metrics.record_result(&result);
+            Response::from_string(result.to_string()).with_header(content_type)
+        }
+
+        // Diagnostic notation endpoint
+        (&Method::Post, "/diagnostic") => {
+            let result = with_hex_field(body, |hex, _| diagnostic_cbor(hex));
+            metrics.record_result(&result);
+            Response::from_string(result.to_string()).with_header(content_type)
+        }
+
+        // Validate endpoint
+        (&Method::Post, "/validate") => {
+            let result = with_hex_field(body, |hex, _| validate_cbor(hex));
+            metrics.record_result(&result);
+            Response::from_string(result.to_string()).with_header(content_type)
+        }
+
+        // Round-trip endpoint
+        (&Method::Post, "/roundtrip") => {
+            let result = with_hex_field(body, |hex, _| roundtrip_cbor(hex));
+            metrics.record_result(&result);
+            Response::from_string(result.to_string()).with_header(content_type)
         }
-        JsonValue::Object(obj) => {
-            // Check for special markers
-            if let Some(bytes_hex) = obj.get("__cbor_bytes__") {
-                if let Some(hex_str) = bytes_hex.as_str() {
-                    if let Ok(bytes) = hex::decode(hex_str) {
-                        return Value::Bytes(bytes);
+
+        // Top-level map keys only
+        (&Method::Post, "/keys") => {
+            let result = with_hex_field(body, |hex, _| keys_cbor(hex));
+            metrics.record_result(&result);
+            Response::from_string(result.to_string()).with_header(content_type)
+        }
+
+        // JSON -> CBOR -> JSON in one call
+        (&Method::Post, "/transcode") => {
+            let result = match read_json_body(body) {
+                Ok(json) => match json.get("value") {
+                    Some(value) => transcode_json(value.clone(), &json),
+                    None => error_response(ErrorCode::MissingField, "Missing \"value\" field"),
+                },
+                Err(e) => e,
+            };
+            metrics.record_result(&result);
+            Response::from_string(result.to_string()).with_header(content_type)
+        }
+
+        // Repeated timing of one operation
+        (&Method::Post, "/bench") => {
+            let result = with_hex_field(body, bench_cbor);
+            metrics.record_result(&result);
+            Response::from_string(result.to_string()).with_header(content_type)
+        }
+
+        // Encode endpoint
+        (&Method::Post, "/encode") => {
+            let parsed = read_json_body(body).and_then(|json| {
+                let value = json.get("value").cloned().ok_or_else(|| {
+                    error_response(ErrorCode::MissingField, "Missing \"value\" field")
+                })?;
+                Ok((value, EncodeOptions::from_request(&json)?))
+            });
+
+            // Raw bytes skip the hex layer; errors are still reported as JSON, but with a 400
+            // status so a client expecting CBOR cannot take the error body for output
+            if has_media_type(&request.headers, "Accept", "application/cbor") {
+                let encoded =
+                    parsed.and_then(|(value, options)| encode_cbor_value(value, &options));
+                metrics.record_encode(encoded.is_ok(), started);
+                return match encoded {
+                    Ok(bytes) => {
+                        let cbor_type =
+                            Header::from_bytes("Content-Type", "application/cbor").unwrap();
+                        Response::from_data(bytes).with_header(cbor_type)
                     }
-                }
+                    Err(e) => Response::from_string(e.to_string())
+                        .with_header(content_type)
+                        .with_status_code(400),
+                };
             }
 
-            if let Some(float_str) = obj.get("__cbor_float__") {
-                if let Some(s) = float_str.as_str() {
-                    return match s {
-                        "NaN" => Value::Float(f64::NAN),
-                        "Infinity" => Value::Float(f64::INFINITY),
-                        "-Infinity" => Value::Float(f64::NEG_INFINITY),
-                        _ => Value::Null,
-                    };
+            match parsed {
+                Ok((value, options)) => {
+                    let result = encode_cbor(value, &options);
+                    metrics.record_encode(is_success(&result), started);
+                    Response::from_string(result.to_string()).with_header(content_type)
+                }
+                Err(e) => {
+                    metrics.record_encode(false, started);
+                    Response::from_string(e.to_string()).with_header(content_type)
                 }
             }
+        }
 
-            if let (Some(tag), Some(inner)) = (obj.get("__cbor_tag__"), obj.get("__cbor_value__")) {
-                if let Some(tag_num) = tag.as_u64() {
-                    return Value::Tag(tag_num, Box::new(json_to_cbor(inner.clone())));
+        // Deterministic encoding profile: /encode with canonical and float_shrink forced on
+        (&Method::Post, "/encode-canonical") => {
+            let result = match read_json_body(body) {
+                Ok(json) => match (json.get("value"), EncodeOptions::from_request(&json)) {
+                    (Some(value), Ok(options)) => {
+                        let options = EncodeOptions {
+                            canonical: true,
+                            float_shrink: true,
+                            ..options
+                        };
+                        encode_cbor(value.clone(), &options)
+                    }
+                    (None, _) => error_response(ErrorCode::MissingField, "Missing \"value\" field"),
+                    (_, Err(e)) => e,
+                },
+                Err(e) => e,
+            };
+            metrics.record_encode(is_success(&result), started);
+            Response::from_string(result.to_string()).with_header(content_type)
+        }
+
+        // CBOR sequence encode endpoint
+        (&Method::Post, "/encode-seq") => {
+            let result = match read_json_body(body) {
+                Ok(json) => match (json.get("values"), EncodeOptions::from_request(&json)) {
+                    (Some(JsonValue::Array(values)), Ok(options)) => {
+                        encode_cbor_seq(values.clone(), &options)
+                    }
+                    (Some(JsonValue::Array(_)), Err(e)) => e,
+                    _ => error_response(ErrorCode::MissingField, "Missing \"values\" array"),
+                },
+                Err(e) => e,
+            };
+            metrics.record_encode(is_success(&result), started);
+            Response::from_string(result.to_string()).with_header(content_type)
+        }
+
+        // Diagnostic notation encode endpoint
+        (&Method::Post, "/encode-diag") => {
+            let result = match read_json_body(body) {
+                Ok(json) => {
+                    if let Some(diag) = json.get("diag").and_then(|v| v.as_str()) {
+                        encode_diag(diag)
+                    } else {
+                        error_response(ErrorCode::MissingField, "Missing \"diag\" field")
+                    }
                 }
-            }
+                Err(e) => e,
+            };
+            metrics.record_encode(is_success(&result), started);
+            Response::from_string(result.to_string()).with_header(content_type)
+        }
 
-            if obj.contains_key("__cbor_undefined__") {
-                return Value::Null; // ciborium doesn't have undefined
+        // Shutdown endpoint, only when enabled via TACO_ALLOW_SHUTDOWN=1
+        (&Method::Post, "/shutdown") => {
+            if state.config.allow_shutdown {
+                state.shutdown.store(true, Ordering::Relaxed);
+                let body = json!({"success": true, "status": "shutting down"});
+                Response::from_string(body.to_string()).with_header(content_type)
+            } else {
+                metrics.record_error();
+                let body = error_response(ErrorCode::ShutdownDisabled, "Shutdown is disabled");
+                Response::from_string(body.to_string())
+                    .with_header(content_type)
+                    .with_status_code(403)
             }
+        }
 
-            // Regular map
-            let mut map = Vec::new();
-            for (k, v) in obj {
-                map.push((Value::Text(k), json_to_cbor(v)));
-            }
-            Value::Map(map)
+        // Not found
+        _ => {
+            metrics.record_error();
+            let body = error_response(ErrorCode::NotFound, "Not found");
+            Response::from_string(body.to_string())
+                .with_header(content_type)
+                .with_status_code(404)
         }
     }
 }
 
-/// Decode CBOR hex string
-fn decode_cbor(hex_string: &str) -> JsonValue {
-    let start = Instant::now();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let bytes = match hex::decode(hex_string) {
-        Ok(b) => b,
-        Err(e) => {
-            return json!({
-                "success": false,
-                "error": format!("Invalid hex: {}", e)
-            });
+    fn test_state() -> AppState {
+        AppState {
+            config: ServerConfig {
+                selftest: false,
+                bind: DEFAULT_BIND.to_string(),
+                port: 0,
+                unix_socket: None,
+                allow_shutdown: false,
+                max_body: DEFAULT_MAX_BODY,
+                request_timeout: None,
+                log_requests: false,
+                lenient_json: false,
+            },
+            metrics: Metrics::default(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            next_request_id: AtomicU64::new(1),
         }
-    };
+    }
 
-    let value: Value = match ciborium::from_reader(&bytes[..]) {
-        Ok(v) => v,
-        Err(e) => {
-            return json!({
-                "success": false,
-                "error": format!("CBOR decode error: {}", e)
-            });
+    /// Route one POST through the panic guard, returning the status and body
+    fn post(
+        state: &AppState,
+        path: &str,
+        headers: &[(&'static str, &str)],
+        body: &[u8],
+    ) -> (u16, Vec<u8>) {
+        let mut head = RequestHead {
+            method: Method::Post,
+            url: path.to_string(),
+            headers: vec![Header::from_bytes("Content-Type", "application/json").unwrap()],
+        };
+        for (name, value) in headers {
+            head.headers.retain(|header| !header.field.equiv(name));
+            head.headers
+                .push(Header::from_bytes(*name, *value).unwrap());
         }
-    };
+        let response = route_guarded(&head, body, state, Instant::now());
+        let status = response.status_code().0;
+        (status, response.into_reader().into_inner())
+    }
 
-    let json_result = cbor_to_json(value);
-    let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+    /// A server on an ephemeral localhost port with its worker pool running
+    struct TestServer {
+        server: Arc<Server>,
+        state: Arc<AppState>,
+        workers: Vec<thread::JoinHandle<()>>,
+    }
 
-    json!({
-        "success": true,
-        "result": json_result,
-        "duration_ms": duration_ms
-    })
-}
+    impl TestServer {
+        fn start() -> Self {
+            Self::serve(Server::http("127.0.0.1:0").unwrap(), test_state())
+        }
+
+        /// Listen on a Unix domain socket at `path` the way main does for TACO_UNIX_SOCKET
+        fn start_unix(path: &Path) -> Self {
+            remove_stale_socket(path);
+            let mut state = test_state();
+            state.config.unix_socket = Some(path.to_path_buf());
+            Self::serve(Server::http_unix(path).unwrap(), state)
+        }
+
+        fn serve(server: Server, state: AppState) -> Self {
+            let server = Arc::new(server);
+            let state = Arc::new(state);
+            let workers = spawn_workers(&server, &state);
+            TestServer {
+                server,
+                state,
+                workers,
+            }
+        }
+
+        /// Send one request over a fresh connection, returning the status and body
+        fn request(&self, method: &str, path: &str, body: &str) -> (u16, String) {
+            match self.server.server_addr() {
+                tiny_http::ListenAddr::IP(addr) => {
+                    exchange(TcpStream::connect(addr).unwrap(), method, path, body)
+                }
+                tiny_http::ListenAddr::Unix(addr) => {
+                    let socket = addr.as_pathname().unwrap();
+                    exchange(UnixStream::connect(socket).unwrap(), method, path, body)
+                }
+            }
+        }
+
+        fn stop(self) {
+            self.state.shutdown.store(true, Ordering::Relaxed);
+            for worker in self.workers {
+                worker.join().unwrap();
+            }
+        }
+    }
 
-/// Encode value to CBOR hex string
-fn encode_cbor(value: JsonValue) -> JsonValue {
-    let start = Instant::now();
+    /// Write an HTTP/1.1 request that closes the connection and read back the whole response
+    fn exchange(
+        mut stream: impl Read + Write,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> (u16, String) {
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+        (status, body)
+    }
 
-    let cbor_value = json_to_cbor(value);
+    fn post_json(state: &AppState, path: &str, body: JsonValue) -> JsonValue {
+        let (_, body) = post(state, path, &[], body.to_string().as_bytes());
+        serde_json::from_slice(&body).unwrap()
+    }
 
-    let mut bytes = Vec::new();
-    if let Err(e) = ciborium::into_writer(&cbor_value, &mut bytes) {
-        return json!({
-            "success": false,
-            "error": format!("CBOR encode error: {}", e)
+    /// How long a /health probe may take while another worker is busy
+    const HEALTH_DEADLINE: std::time::Duration = std::time::Duration::from_millis(500);
+
+    #[test]
+    fn health_stays_responsive_during_slow_decode() {
+        let server = TestServer::start();
+        // A 200,000-element array keeps one worker busy converting for a while
+        let slow = json!({"hex": format!("9a00030d40{}", "00".repeat(200_000))}).to_string();
+        let slow_done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let slow_request = scope.spawn(|| {
+                let (status, _) = server.request("POST", "/decode", &slow);
+                slow_done.store(true, Ordering::Relaxed);
+                assert_eq!(status, 200);
+            });
+
+            // Every probe answered while the decode is still running must be quick
+            let mut overlapping = 0;
+            while !slow_done.load(Ordering::Relaxed) {
+                let started = Instant::now();
+                let (status, _) = server.request("GET", "/health", "");
+                let took = started.elapsed();
+                assert_eq!(status, 200);
+                if !slow_done.load(Ordering::Relaxed) {
+                    assert!(took < HEALTH_DEADLINE, "health took {:?}", took);
+                    overlapping += 1;
+                }
+            }
+            slow_request.join().unwrap();
+            assert!(overlapping > 0, "no health probe overlapped the decode");
         });
+        server.stop();
     }
 
-    let hex_string = hex::encode(&bytes);
-    let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+    #[test]
+    fn encode_returns_raw_cbor_on_accept() {
+        let state = test_state();
+        let accept = [("Accept", "application/cbor")];
+        for value in [
+            json!([1, "a", {"b": 1.5}]),
+            json!({"__cbor_undefined__": true}),
+        ] {
+            let body = json!({"value": value}).to_string();
+            let (status, raw) = post(&state, "/encode", &accept, body.as_bytes());
+            assert_eq!(status, 200);
+            let json_mode = post_json(&state, "/encode", json!({"value": value}));
+            assert_eq!(json_mode["hex"], hex::encode(raw));
+        }
 
-    json!({
-        "success": true,
-        "hex": hex_string,
-        "duration_ms": duration_ms
-    })
-}
+        // Errors keep their JSON body but are not a 200 the client could read as CBOR
+        for body in [
+            r#"{"value": {"__cbor_simple__": 24}}"#,
+            r#"{"valu": 1}"#,
+            "not json",
+        ] {
+            let (status, error) = post(&state, "/encode", &accept, body.as_bytes());
+            assert_eq!(status, 400, "{}", body);
+            let error: JsonValue = serde_json::from_slice(&error).unwrap();
+            assert_eq!(error["success"], false);
+        }
+    }
 
-fn main() {
-    let addr = format!("0.0.0.0:{}", PORT);
-    let server = Server::http(&addr).expect("Failed to start server");
+    #[test]
+    fn unsupported_content_type_answers_415() {
+        let state = test_state();
+        let body = br#"{"hex": "01", "value": 1}"#;
+        for path in ["/decode", "/encode"] {
+            let (status, error) = post(&state, path, &[("Content-Type", "text/plain")], body);
+            assert_eq!(status, 415, "{}", path);
+            let error: JsonValue = serde_json::from_slice(&error).unwrap();
+            assert_eq!(error["error"], "unsupported media type");
 
-    println!(
-        "CBOR test container ({} {}) listening on port {}",
-        LIBRARY_NAME, LIBRARY_VERSION, PORT
-    );
+            let json_type = [("Content-Type", "application/json; charset=utf-8")];
+            assert_eq!(post(&state, path, &json_type, body).0, 200, "{}", path);
+        }
+        // Raw CBOR is only read by /decode
+        let cbor = [("Content-Type", "application/cbor")];
+        assert_eq!(post(&state, "/decode", &cbor, &[0x01]).0, 200);
+        assert_eq!(post(&state, "/encode", &cbor, &[0x01]).0, 415);
+    }
 
-    for mut request in server.incoming_requests() {
-        let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    #[test]
+    fn encode_canonical_matches_encode_with_flags() {
+        let state = test_state();
+        for value in [
+            json!({"b": 1, "a": [1.5, {"z": 0, "c": 100000.0}]}),
+            json!({"__cbor_undefined__": true}),
+            json!({"__cbor_simple__": 16}),
+            json!({"k": [{"__cbor_undefined__": true}, {"__cbor_simple__": 255}]}),
+        ] {
+            let shortcut = post_json(&state, "/encode-canonical", json!({"value": value}));
+            let flagged = post_json(
+                &state,
+                "/encode",
+                json!({"value": value, "canonical": true, "float_shrink": true}),
+            );
+            assert_eq!(shortcut["hex"], flagged["hex"], "{}", value);
 
-        let response = match (request.method(), request.url()) {
-            // Health check
-            (&Method::Get, "/health") => {
-                let body = json!({
-                    "status": "ok",
-                    "library": LIBRARY_NAME,
-                    "version": LIBRARY_VERSION,
-                    "language": LANGUAGE
-                });
-                Response::from_string(body.to_string()).with_header(content_type)
-            }
+            // Undefined and simple values survive the trip back through /decode
+            let decoded = post_json(&state, "/decode", json!({"hex": shortcut["hex"]}));
+            assert_eq!(decoded["result"], value);
+        }
+    }
 
-            // Decode endpoint
-            (&Method::Post, "/decode") => {
-                let mut body = String::new();
-                request.as_reader().read_to_string(&mut body).unwrap_or(0);
-
-                let result = match serde_json::from_str::<JsonValue>(&body) {
-                    Ok(json) => {
-                        if let Some(hex) = json.get("hex").and_then(|v| v.as_str()) {
-                            decode_cbor(hex)
-                        } else {
-                            json!({"success": false, "error": "Missing \"hex\" field"})
-                        }
-                    }
-                    Err(e) => json!({"success": false, "error": format!("Invalid JSON: {}", e)}),
-                };
+    #[test]
+    fn panicking_handler_answers_500() {
+        let metrics = Metrics::default();
+        let response = guard_panics(&metrics, || panic!("handler panicked"));
+        assert_eq!(response.status_code().0, 500);
+        let body: JsonValue = serde_json::from_slice(&response.into_reader().into_inner()).unwrap();
+        assert_eq!(body["success"], false);
+        assert_eq!(body["error_code"], "panic");
+        assert_eq!(body["error"], "internal panic: handler panicked");
 
-                Response::from_string(result.to_string()).with_header(content_type)
-            }
+        // Formatted messages are String payloads rather than &str
+        let response = guard_panics(&metrics, || panic!("depth {}", 3));
+        let body: JsonValue = serde_json::from_slice(&response.into_reader().into_inner()).unwrap();
+        assert_eq!(body["error"], "internal panic: depth 3");
+        assert_eq!(metrics.errors.load(Ordering::Relaxed), 2);
 
-            // Encode endpoint
-            (&Method::Post, "/encode") => {
-                let mut body = String::new();
-                request.as_reader().read_to_string(&mut body).unwrap_or(0);
-
-                let result = match serde_json::from_str::<JsonValue>(&body) {
-                    Ok(json) => {
-                        if let Some(value) = json.get("value") {
-                            encode_cbor(value.clone())
-                        } else {
-                            json!({"success": false, "error": "Missing \"value\" field"})
-                        }
-                    }
-                    Err(e) => json!({"success": false, "error": format!("Invalid JSON: {}", e)}),
-                };
+        // A handler that returns is passed through untouched
+        let response = guard_panics(&metrics, || Response::from_string("ok"));
+        assert_eq!(response.status_code().0, 200);
+        assert_eq!(metrics.errors.load(Ordering::Relaxed), 2);
+    }
 
-                Response::from_string(result.to_string()).with_header(content_type)
+    #[test]
+    fn empty_bodies_answer_empty_body() {
+        let state = test_state();
+        for path in ["/decode", "/encode"] {
+            for body in ["", "  \n"] {
+                let (_, error) = post(&state, path, &[], body.as_bytes());
+                let error: JsonValue = serde_json::from_slice(&error).unwrap();
+                assert_eq!(error["error_code"], "empty_body");
+                assert_eq!(error["error"], "request body is empty");
             }
+        }
+        let cbor = [("Content-Type", "application/cbor")];
+        let (_, error) = post(&state, "/decode", &cbor, b"");
+        let error: JsonValue = serde_json::from_slice(&error).unwrap();
+        assert_eq!(error["error_code"], "empty_body");
+    }
 
-            // Not found
-            _ => {
-                let body = json!({"error": "Not found"});
-                Response::from_string(body.to_string())
-                    .with_header(content_type)
-                    .with_status_code(404)
+    #[test]
+    fn lenient_json_encodes_bare_infinity() {
+        let mut state = test_state();
+        state.config.lenient_json = true;
+        let server = TestServer::serve(Server::http("127.0.0.1:0").unwrap(), state);
+        let (_, body) = server.request("POST", "/encode", r#"{"value": Infinity}"#);
+        let encoded: JsonValue = serde_json::from_str(&body).unwrap();
+        assert_eq!(encoded["hex"], "f97c00");
+        let (_, body) = server.request("POST", "/encode", r#"{"value": [-Infinity, NaN, "NaN"]}"#);
+        let encoded: JsonValue = serde_json::from_str(&body).unwrap();
+        assert_eq!(encoded["hex"], "83f9fc00f97e00634e614e");
+        server.stop();
+
+        // Strings and longer words are left alone
+        assert_eq!(
+            lenient_json(br#"["Infinity", Infinityx, -Infinity]"#.to_vec()),
+            br#"["Infinity", Infinityx, {"__cbor_float__":"-Infinity"}]"#
+        );
+
+        // Without the mode the body is plain JSON, which has no such token
+        let server = TestServer::start();
+        let (_, body) = server.request("POST", "/encode", r#"{"value": Infinity}"#);
+        let error: JsonValue = serde_json::from_str(&body).unwrap();
+        assert_eq!(error["error_code"], "invalid_json");
+        server.stop();
+    }
+
+    #[test]
+    fn serves_over_a_unix_socket() {
+        let path = std::env::temp_dir().join(format!("taco-test-{}.sock", std::process::id()));
+        // A socket file left by an earlier run is replaced rather than failing the bind
+        drop(std::os::unix::net::UnixListener::bind(&path));
+        let server = TestServer::start_unix(&path);
+
+        let (status, body) = server.request("GET", "/health", "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_str::<JsonValue>(&body).unwrap()["status"],
+            "ok"
+        );
+        let (status, body) = server.request("POST", "/encode", r#"{"value": [1, "a"]}"#);
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_str::<JsonValue>(&body).unwrap()["hex"],
+            "82016161"
+        );
+        assert!(healthcheck(&server.state.config));
+
+        server.stop();
+        let _ = std::fs::remove_file(&path);
+        let mut config = test_state().config;
+        config.unix_socket = Some(path);
+        assert!(!healthcheck(&config));
+    }
+
+    #[test]
+    fn worker_pool_answers_parallel_requests() {
+        let server = TestServer::start();
+        thread::scope(|scope| {
+            for client in 0..8 {
+                let server = &server;
+                scope.spawn(move || {
+                    for i in 0..25 {
+                        let n = client * 1000 + i;
+                        let body = json!({"value": [n, format!("item {}", n)]}).to_string();
+                        let (status, body) = server.request("POST", "/encode", &body);
+                        assert_eq!(status, 200);
+                        let result: JsonValue = serde_json::from_str(&body).unwrap();
+                        let expected = encode_cbor_value(
+                            json!([n, format!("item {}", n)]),
+                            &EncodeOptions::default(),
+                        )
+                        .unwrap();
+                        assert_eq!(result["hex"], hex::encode(expected));
+                    }
+                });
             }
-        };
+        });
+        assert_eq!(server.state.metrics.requests.load(Ordering::Relaxed), 200);
+        server.stop();
+    }
 
-        let _ = request.respond(response);
+    #[test]
+    fn healthcheck_probes_the_bound_port() {
+        let server = TestServer::start();
+        let port = server.server.server_addr().to_ip().unwrap().port();
+        for bind in ["0.0.0.0", "127.0.0.1"] {
+            let mut config = test_state().config;
+            config.bind = bind.to_string();
+            config.port = port;
+            assert!(healthcheck(&config), "{}", bind);
+        }
+        server.stop();
     }
 }
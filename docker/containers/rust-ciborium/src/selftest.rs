@@ -0,0 +1,287 @@
+//! Startup self-check against the RFC 8949 Appendix A examples, enabled with TACO_SELFTEST=1
+
+use crate::{decode_cbor_bytes, encode_cbor_value, DecodeOptions, EncodeOptions};
+use serde_json::{json, Value as JsonValue};
+
+/// Appendix A examples as (encoding, what /decode returns, whether /encode restores the bytes)
+///
+/// Encode writes preferred serialization, so wide or indefinite-length encodings come back
+/// shorter; those are only checked to decode to the same value again. Integer map keys
+/// become text keys in JSON, so that example is not byte-exact either.
+const APPENDIX_A: &[(&str, &str, bool)] = &[
+    // 0
+    ("00", "0", true),
+    // 1
+    ("01", "1", true),
+    // 10
+    ("0a", "10", true),
+    // 23
+    ("17", "23", true),
+    // 24
+    ("1818", "24", true),
+    // 25
+    ("1819", "25", true),
+    // 100
+    ("1864", "100", true),
+    // 1000
+    ("1903e8", "1000", true),
+    // 1000000
+    ("1a000f4240", "1000000", true),
+    // 1000000000000
+    ("1b000000e8d4a51000", "1000000000000", true),
+    // 18446744073709551615
+    ("1bffffffffffffffff", r#""18446744073709551615""#, true),
+    // 18446744073709551616
+    (
+        "c249010000000000000000",
+        r#"{"__cbor_bignum__":"18446744073709551616"}"#,
+        true,
+    ),
+    // -18446744073709551616
+    ("3bffffffffffffffff", r#""-18446744073709551616""#, true),
+    // -18446744073709551617
+    (
+        "c349010000000000000000",
+        r#"{"__cbor_bignum__":"-18446744073709551617"}"#,
+        true,
+    ),
+    // -1
+    ("20", "-1", true),
+    // -10
+    ("29", "-10", true),
+    // -100
+    ("3863", "-100", true),
+    // -1000
+    ("3903e7", "-1000", true),
+    // 0.0
+    ("f90000", "0.0", true),
+    // -0.0
+    ("f98000", r#"{"__cbor_float__":"-0"}"#, true),
+    // 1.0
+    ("f93c00", "1.0", true),
+    // 1.1
+    ("fb3ff199999999999a", "1.1", true),
+    // 1.5
+    ("f93e00", "1.5", true),
+    // 65504.0
+    ("f97bff", "65504.0", true),
+    // 100000.0
+    ("fa47c35000", "100000.0", true),
+    // 3.4028234663852886e+38
+    ("fa7f7fffff", "3.4028234663852886e+38", true),
+    // 1.0e+300
+    ("fb7e37e43c8800759c", "1e+300", true),
+    // 5.960464477539063e-8
+    ("f90001", "5.960464477539063e-08", true),
+    // 0.00006103515625
+    ("f90400", "6.103515625e-05", true),
+    // -4.0
+    ("f9c400", "-4.0", true),
+    // -4.1
+    ("fbc010666666666666", "-4.1", true),
+    // Infinity
+    ("f97c00", r#"{"__cbor_float__":"Infinity"}"#, true),
+    // NaN
+    ("f97e00", r#"{"__cbor_float__":"NaN"}"#, true),
+    // -Infinity
+    ("f9fc00", r#"{"__cbor_float__":"-Infinity"}"#, true),
+    // Infinity
+    ("fa7f800000", r#"{"__cbor_float__":"Infinity"}"#, false),
+    // NaN
+    ("fa7fc00000", r#"{"__cbor_float__":"NaN"}"#, false),
+    // -Infinity
+    ("faff800000", r#"{"__cbor_float__":"-Infinity"}"#, false),
+    // Infinity
+    (
+        "fb7ff0000000000000",
+        r#"{"__cbor_float__":"Infinity"}"#,
+        false,
+    ),
+    // NaN
+    ("fb7ff8000000000000", r#"{"__cbor_float__":"NaN"}"#, false),
+    // -Infinity
+    (
+        "fbfff0000000000000",
+        r#"{"__cbor_float__":"-Infinity"}"#,
+        false,
+    ),
+    // false
+    ("f4", "false", true),
+    // true
+    ("f5", "true", true),
+    // null
+    ("f6", "null", true),
+    // undefined
+    ("f7", r#"{"__cbor_undefined__":true}"#, true),
+    // simple(16)
+    ("f0", r#"{"__cbor_simple__":16}"#, true),
+    // simple(255)
+    ("f8ff", r#"{"__cbor_simple__":255}"#, true),
+    // 0("2013-03-21T20:04:00Z")
+    (
+        "c074323031332d30332d32315432303a30343a30305a",
+        r#"{"__cbor_datetime__":"2013-03-21T20:04:00Z"}"#,
+        true,
+    ),
+    // 1(1363896240)
+    ("c11a514b67b0", r#"{"__cbor_epoch__":1363896240}"#, true),
+    // 1(1363896240.5)
+    (
+        "c1fb41d452d9ec200000",
+        r#"{"__cbor_epoch__":1363896240.5}"#,
+        true,
+    ),
+    // 23(h'01020304')
+    (
+        "d74401020304",
+        r#"{"__cbor_tag__":23,"__cbor_value__":{"__cbor_bytes__":"01020304"}}"#,
+        true,
+    ),
+    // 24(h'6449455446')
+    (
+        "d818456449455446",
+        r#"{"__cbor_tag__":24,"__cbor_value__":{"__cbor_bytes__":"6449455446"}}"#,
+        true,
+    ),
+    // 32("http://www.example.com")
+    (
+        "d82076687474703a2f2f7777772e6578616d706c652e636f6d",
+        r#"{"__cbor_uri__":"http://www.example.com"}"#,
+        true,
+    ),
+    // h''
+    ("40", r#"{"__cbor_bytes__":""}"#, true),
+    // h'01020304'
+    ("4401020304", r#"{"__cbor_bytes__":"01020304"}"#, true),
+    // ""
+    ("60", r#""""#, true),
+    // "a"
+    ("6161", r#""a""#, true),
+    // "IETF"
+    ("6449455446", r#""IETF""#, true),
+    // "\"\\"
+    ("62225c", r#""\"\\""#, true),
+    // "ü"
+    ("62c3bc", r#""ü""#, true),
+    // "水"
+    ("63e6b0b4", r#""水""#, true),
+    // "𐅑"
+    ("64f0908591", r#""𐅑""#, true),
+    // []
+    ("80", "[]", true),
+    // [1, 2, 3]
+    ("83010203", "[1,2,3]", true),
+    // [1, [2, 3], [4, 5]]
+    ("8301820203820405", "[1,[2,3],[4,5]]", true),
+    // [1, 2, 3, ..., 25]
+    (
+        "98190102030405060708090a0b0c0d0e0f101112131415161718181819",
+        "[1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25]",
+        true,
+    ),
+    // {}
+    ("a0", "{}", true),
+    // {1: 2, 3: 4}
+    ("a201020304", r#"{"1":2,"3":4}"#, false),
+    // {"a": 1, "b": [2, 3]}
+    ("a26161016162820203", r#"{"a":1,"b":[2,3]}"#, true),
+    // ["a", {"b": "c"}]
+    ("826161a161626163", r#"["a",{"b":"c"}]"#, true),
+    // {"a": "A", "b": "B", "c": "C", "d": "D", "e": "E"}
+    (
+        "a56161614161626142616361436164614461656145",
+        r#"{"a":"A","b":"B","c":"C","d":"D","e":"E"}"#,
+        true,
+    ),
+    // (_ h'0102', h'030405')
+    (
+        "5f42010243030405ff",
+        r#"{"__cbor_bytes__":"0102030405"}"#,
+        false,
+    ),
+    // (_ "strea", "ming")
+    ("7f657374726561646d696e67ff", r#""streaming""#, false),
+    // [_ ]
+    ("9fff", "[]", false),
+    // [_ 1, [2, 3], [_ 4, 5]]
+    ("9f018202039f0405ffff", "[1,[2,3],[4,5]]", false),
+    // [_ 1, [2, 3], [4, 5]]
+    ("9f01820203820405ff", "[1,[2,3],[4,5]]", false),
+    // [1, [2, 3], [_ 4, 5]]
+    ("83018202039f0405ff", "[1,[2,3],[4,5]]", false),
+    // [1, [_ 2, 3], [4, 5]]
+    ("83019f0203ff820405", "[1,[2,3],[4,5]]", false),
+    // [_ 1, 2, 3, ..., 25]
+    (
+        "9f0102030405060708090a0b0c0d0e0f101112131415161718181819ff",
+        "[1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25]",
+        false,
+    ),
+    // {_ "a": 1, "b": [_ 2, 3]}
+    ("bf61610161629f0203ffff", r#"{"a":1,"b":[2,3]}"#, false),
+    // ["a", {_ "b": "c"}]
+    ("826161bf61626163ff", r#"["a",{"b":"c"}]"#, false),
+    // {_ "Fun": true, "Amt": -2}
+    (
+        "bf6346756ef563416d7421ff",
+        r#"{"Amt":-2,"Fun":true}"#,
+        false,
+    ),
+];
+
+/// Run every example through decode and re-encode, returning the number checked or the failures
+pub(crate) fn run() -> Result<usize, Vec<String>> {
+    let failures: Vec<String> = APPENDIX_A
+        .iter()
+        .filter_map(|&(hex, expected, exact)| {
+            check(hex, expected, exact)
+                .err()
+                .map(|e| format!("{}: {}", hex, e))
+        })
+        .collect();
+
+    if failures.is_empty() {
+        Ok(APPENDIX_A.len())
+    } else {
+        Err(failures)
+    }
+}
+
+fn check(hex: &str, expected: &str, exact: bool) -> Result<(), String> {
+    let bytes = hex::decode(hex).map_err(|e| format!("invalid vector hex: {}", e))?;
+    let expected: JsonValue =
+        serde_json::from_str(expected).map_err(|e| format!("invalid vector JSON: {}", e))?;
+
+    let decoded = decode(&bytes)?;
+    if decoded != expected {
+        return Err(format!("decoded to {}, expected {}", decoded, expected));
+    }
+
+    let options = EncodeOptions::default();
+    let reencoded = encode_cbor_value(decoded, &options).map_err(|e| e["error"].to_string())?;
+    if exact && reencoded != bytes {
+        return Err(format!("re-encoded to {}", hex::encode(&reencoded)));
+    }
+
+    let redecoded = decode(&reencoded)?;
+    if redecoded != expected {
+        return Err(format!(
+            "re-encoded to {}, which decodes to {}",
+            hex::encode(&reencoded),
+            redecoded
+        ));
+    }
+    Ok(())
+}
+
+/// Decode with default options, folding a top-level tag back into the value
+fn decode(bytes: &[u8]) -> Result<JsonValue, String> {
+    let response = decode_cbor_bytes(bytes, &DecodeOptions::default());
+    if response["success"] != true {
+        return Err(format!("decode failed: {}", response["error"]));
+    }
+    Ok(match response.get("tag") {
+        Some(tag) => json!({ "__cbor_tag__": tag, "__cbor_value__": response["result"] }),
+        None => response["result"].clone(),
+    })
+}
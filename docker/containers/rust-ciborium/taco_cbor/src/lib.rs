@@ -0,0 +1,5323 @@
+//! CBOR <-> JSON conversion shared by the HTTP test container and the cbor_bridge CLI
+//!
+//! The entry points are [`decode_cbor_bytes`] and [`encode_cbor_value`], which take the same
+//! options as the container's /decode and /encode, and the lower-level [`cbor_to_json`] and
+//! [`json_to_cbor`] they are built on.
+
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::{alphabet, Engine};
+use ciborium::value::Value;
+use half::f16;
+use serde_json::{json, Value as JsonValue};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{Cursor, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Instant;
+
+/// Largest integer magnitude JavaScript represents exactly (2^53 - 1)
+const MAX_SAFE_INTEGER: u64 = 9007199254740991;
+
+/// Headers of the encoded output described by the explain option
+const EXPLAIN_HEADERS: usize = 16;
+
+/// Iterations /bench runs when the request does not say
+const DEFAULT_BENCH_ITERATIONS: u64 = 100;
+/// Most iterations one /bench request may ask for
+const MAX_BENCH_ITERATIONS: u64 = 100_000;
+
+/// Default and ceiling for the nesting depth limit; ciborium itself stops at 256 levels
+const MAX_DEPTH: usize = 256;
+
+/// Start of the scan error for additional information 28-30, which RFC 8949 reserves
+const RESERVED_INFO_ERROR: &str = "reserved additional information";
+
+/// Simple values ciborium maps to Value::Null
+const SIMPLE_NULL: u8 = 22;
+const SIMPLE_UNDEFINED: u8 = 23;
+/// Tag number mask_simple_values gives negative bignums ciborium would overflow on
+const MASKED_BIGNUM_TAG: u8 = 23;
+
+/// Unpadded base64url output that accepts input with or without padding
+const BASE64URL: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Standard base64 for `__cbor_bytes__`, padded on output and either way on input
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Stable machine-readable category of an error response, reported as "error_code"
+#[derive(Clone, Copy)]
+pub enum ErrorCode {
+    InvalidJson,
+    EmptyBody,
+    MissingField,
+    UnknownEncoding,
+    UnknownCanonicalMode,
+    UnknownMajor,
+    MajorMismatch,
+    PathNotFound,
+    InvalidTagContent,
+    UnknownOperation,
+    InvalidIterations,
+    IndefiniteForbidden,
+    OutputTooLarge,
+    UnknownTagHandler,
+    InvalidHex,
+    InvalidBase64,
+    CborDecode,
+    CborEncode,
+    DepthExceeded,
+    DuplicateKey,
+    TrailingBytes,
+    ReservedEncoding,
+    InvalidDiagnostic,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    Panic,
+    NotArray,
+    NotMap,
+    Timeout,
+    ShutdownDisabled,
+    NotFound,
+    /// The cbor_bridge CLI was called with missing or malformed arguments
+    Usage,
+    /// The cbor_bridge CLI was asked for an action it does not have
+    UnknownAction,
+    /// The cbor_bridge CLI could not read its input file or stdin
+    ReadFailed,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidJson => "invalid_json",
+            ErrorCode::EmptyBody => "empty_body",
+            ErrorCode::MissingField => "missing_field",
+            ErrorCode::UnknownEncoding => "unknown_encoding",
+            ErrorCode::UnknownCanonicalMode => "unknown_canonical_mode",
+            ErrorCode::UnknownMajor => "unknown_major",
+            ErrorCode::MajorMismatch => "major_mismatch",
+            ErrorCode::PathNotFound => "path_not_found",
+            ErrorCode::InvalidTagContent => "invalid_tag_content",
+            ErrorCode::UnknownOperation => "unknown_operation",
+            ErrorCode::InvalidIterations => "invalid_iterations",
+            ErrorCode::IndefiniteForbidden => "indefinite_forbidden",
+            ErrorCode::OutputTooLarge => "output_too_large",
+            ErrorCode::UnknownTagHandler => "unknown_tag_handler",
+            ErrorCode::InvalidHex => "invalid_hex",
+            ErrorCode::InvalidBase64 => "invalid_base64",
+            ErrorCode::CborDecode => "cbor_decode",
+            ErrorCode::CborEncode => "cbor_encode",
+            ErrorCode::DepthExceeded => "depth_exceeded",
+            ErrorCode::DuplicateKey => "duplicate_key",
+            ErrorCode::TrailingBytes => "trailing_bytes",
+            ErrorCode::ReservedEncoding => "reserved_encoding",
+            ErrorCode::InvalidDiagnostic => "invalid_diagnostic",
+            ErrorCode::PayloadTooLarge => "payload_too_large",
+            ErrorCode::UnsupportedMediaType => "unsupported_media_type",
+            ErrorCode::Panic => "panic",
+            ErrorCode::NotArray => "not_array",
+            ErrorCode::NotMap => "not_map",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::ShutdownDisabled => "shutdown_disabled",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Usage => "usage",
+            ErrorCode::UnknownAction => "unknown_action",
+            ErrorCode::ReadFailed => "read_failed",
+        }
+    }
+}
+
+/// Build a failure response carrying both the error code and a human-readable message
+pub fn error_response(code: ErrorCode, message: impl Into<String>) -> JsonValue {
+    json!({
+        "success": false,
+        "error_code": code.as_str(),
+        "error": message.into()
+    })
+}
+
+/// Options accepted alongside "hex" in a /decode request
+#[derive(Clone)]
+pub struct DecodeOptions {
+    /// Fail instead of letting a repeated map key overwrite an earlier entry
+    pub strict_maps: bool,
+    /// Integers with a larger magnitude are emitted as strings
+    pub max_safe_int: u64,
+    /// Emit every unsigned integer up to u64::MAX as a JSON number, whatever max_safe_int says
+    pub preserve_u64: bool,
+    /// Deepest nesting of arrays, maps and tags accepted before decoding
+    pub depth_limit: usize,
+    /// How the "hex" payload field is encoded
+    pub encoding: PayloadEncoding,
+    /// Emit maps with integer keys as `__cbor_map_entries__` pairs instead of stringifying keys
+    pub preserve_int_keys: bool,
+    /// Emit every map as `__cbor_map_entries__` pairs, keeping all key types and the wire order
+    pub maps_as_pairs: bool,
+    /// Add a `__cbor_bytes_len__` field with the decoded length next to each `__cbor_bytes__`
+    pub verbose_bytes: bool,
+    /// How byte strings are written inside `__cbor_bytes__`
+    pub bytes_encoding: BytesEncoding,
+    /// Report whether any integer, length or tag argument used more bytes than necessary
+    pub check_minimal: bool,
+    /// Report whether the item is already in RFC 8949 deterministic form as `is_canonical`
+    pub check_canonical: bool,
+    /// Reject reserved additional information (28-30) with a dedicated error before decoding
+    pub strict: bool,
+    /// Decode the byte string inside tag 24 (encoded CBOR data item) as `__cbor_encoded_cbor__`
+    pub decode_embedded: bool,
+    /// Annotate each float with its wire width as `{"__cbor_float_width__": 16, "value": ...}`
+    pub float_subtype: bool,
+    /// Emit every map as `__cbor_ordered_map__` pairs in wire order
+    pub ordered: bool,
+    /// Add the raw payload of each NaN as `__cbor_nan_bits__` hex next to its `__cbor_float__`
+    pub nan_bits: bool,
+    /// Report how many items of each major type the decoded value holds as `type_histogram`
+    pub stats: bool,
+    /// Fail with `trailing_bytes` instead of ignoring bytes after the first item
+    pub reject_trailing: bool,
+    /// List what the JSON result cannot carry back to the original bytes as `warnings`
+    pub warnings: bool,
+    /// Add an `offsets` tree giving the wire position and length of every item
+    pub offsets: bool,
+    /// Dotted path of object keys and array indices; only the value there is returned
+    pub path: Option<String>,
+    /// Fail with `indefinite_forbidden` if any string, array or map has indefinite length
+    pub reject_indefinite: bool,
+    /// Largest serialized JSON "result" allowed, in bytes
+    pub max_output_bytes: Option<usize>,
+    /// Tag handlers applied; tags without an enabled handler become `__cbor_tag__` markers
+    pub tag_handlers: Vec<&'static TagHandler>,
+    /// Most items converted per array and entries per map; the rest are only counted
+    ///
+    /// A truncated array ends with a `{"__cbor_truncated__": true, "remaining": M}` element.
+    /// A truncated map (in any of its JSON forms) gains `"__cbor_truncated__": true` and
+    /// `"__cbor_remaining__": M` members, named so they cannot collide with a real key.
+    pub limit: Option<usize>,
+    /// Emit every finite float as its shortest round-trip decimal in a `__cbor_float__` string
+    pub float_as_string: bool,
+    /// Decode non-empty byte strings directly inside a top-level array (optionally tagged) as
+    /// `__cbor_bstr_cbor__`, the way COSE wraps headers and payloads; others stay byte strings
+    pub cose: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            strict_maps: false,
+            max_safe_int: MAX_SAFE_INTEGER,
+            preserve_u64: false,
+            depth_limit: MAX_DEPTH,
+            encoding: PayloadEncoding::Hex,
+            bytes_encoding: BytesEncoding::Hex,
+            preserve_int_keys: false,
+            maps_as_pairs: false,
+            verbose_bytes: false,
+            check_minimal: false,
+            check_canonical: false,
+            strict: false,
+            decode_embedded: false,
+            float_subtype: false,
+            ordered: false,
+            nan_bits: false,
+            stats: false,
+            reject_trailing: false,
+            warnings: false,
+            offsets: false,
+            path: None,
+            reject_indefinite: false,
+            max_output_bytes: None,
+            tag_handlers: TAG_HANDLERS.iter().collect(),
+            limit: None,
+            float_as_string: false,
+            cose: false,
+        }
+    }
+}
+
+impl DecodeOptions {
+    pub fn from_request(json: &JsonValue) -> Result<Self, JsonValue> {
+        let defaults = DecodeOptions::default();
+        Ok(DecodeOptions {
+            strict_maps: json
+                .get("strict_maps")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.strict_maps),
+            max_safe_int: json
+                .get("max_safe_int")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(defaults.max_safe_int),
+            preserve_u64: json
+                .get("preserve_u64")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.preserve_u64),
+            depth_limit: depth_limit_from_request(json),
+            encoding: PayloadEncoding::from_request(json)?,
+            bytes_encoding: BytesEncoding::from_request(json)?,
+            preserve_int_keys: json
+                .get("preserve_int_keys")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.preserve_int_keys),
+            maps_as_pairs: json
+                .get("maps_as_pairs")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.maps_as_pairs),
+            verbose_bytes: json
+                .get("verbose_bytes")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.verbose_bytes),
+            check_minimal: json
+                .get("check_minimal")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.check_minimal),
+            check_canonical: json
+                .get("check_canonical")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.check_canonical),
+            strict: json
+                .get("strict")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.strict),
+            decode_embedded: json
+                .get("decode_embedded")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.decode_embedded),
+            float_subtype: json
+                .get("float_subtype")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.float_subtype),
+            ordered: json
+                .get("ordered")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.ordered),
+            nan_bits: json
+                .get("nan_bits")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.nan_bits),
+            stats: json
+                .get("stats")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.stats),
+            reject_trailing: json
+                .get("reject_trailing")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.reject_trailing),
+            warnings: json
+                .get("warnings")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.warnings),
+            offsets: json
+                .get("offsets")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.offsets),
+            path: json
+                .get("path")
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+                .or(defaults.path),
+            reject_indefinite: json
+                .get("reject_indefinite")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.reject_indefinite),
+            max_output_bytes: json
+                .get("max_output_bytes")
+                .and_then(|v| v.as_u64())
+                .map(|n| usize::try_from(n).unwrap_or(usize::MAX))
+                .or(defaults.max_output_bytes),
+            tag_handlers: match json.get("tag_handlers") {
+                Some(names) => tag_handlers_from_request(names)?,
+                None => defaults.tag_handlers,
+            },
+            limit: json
+                .get("limit")
+                .and_then(|v| v.as_u64())
+                .map(|n| usize::try_from(n).unwrap_or(usize::MAX))
+                .or(defaults.limit),
+            float_as_string: json
+                .get("float_as_string")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.float_as_string),
+            cose: json
+                .get("cose")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(defaults.cose),
+        })
+    }
+}
+
+/// Text encoding of the CBOR bytes carried in a request or response "hex" field
+#[derive(Clone, Copy)]
+pub enum PayloadEncoding {
+    Hex,
+    Base64Url,
+}
+
+impl PayloadEncoding {
+    /// Read the optional "encoding" request field, defaulting to hex
+    pub fn from_request(json: &JsonValue) -> Result<Self, JsonValue> {
+        match json.get("encoding").and_then(|v| v.as_str()) {
+            None | Some("hex") => Ok(PayloadEncoding::Hex),
+            Some("base64url") => Ok(PayloadEncoding::Base64Url),
+            Some(other) => Err(error_response(
+                ErrorCode::UnknownEncoding,
+                format!("Unknown encoding: {}", other),
+            )),
+        }
+    }
+
+    /// Decode a payload string into bytes, returning an error response on failure
+    pub fn decode(self, payload: &str) -> Result<Vec<u8>, JsonValue> {
+        match self {
+            PayloadEncoding::Hex => decode_hex(payload),
+            PayloadEncoding::Base64Url => BASE64URL.decode(payload).map_err(|e| {
+                error_response(
+                    ErrorCode::InvalidBase64,
+                    format!("Invalid base64url: {}", e),
+                )
+            }),
+        }
+    }
+
+    pub fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            PayloadEncoding::Hex => hex::encode(bytes),
+            PayloadEncoding::Base64Url => BASE64URL.encode(bytes),
+        }
+    }
+}
+
+/// How the content of a byte string is written in a `__cbor_bytes__` marker
+///
+/// Hex and the numeric array need nothing else to be read back. The base64 forms add
+/// `__cbor_bytes_encoding__` naming the alphabet, since a string such as `"abcd"` is valid
+/// hex as well as base64.
+#[derive(Clone, Copy)]
+pub enum BytesEncoding {
+    Hex,
+    Base64,
+    Base64Url,
+    Array,
+}
+
+impl BytesEncoding {
+    /// Read the optional "bytes_encoding" request field, defaulting to hex
+    pub fn from_request(json: &JsonValue) -> Result<Self, JsonValue> {
+        match json.get("bytes_encoding").and_then(|v| v.as_str()) {
+            None | Some("hex") => Ok(BytesEncoding::Hex),
+            Some("base64") => Ok(BytesEncoding::Base64),
+            Some("base64url") => Ok(BytesEncoding::Base64Url),
+            Some("array") => Ok(BytesEncoding::Array),
+            Some(other) => Err(error_response(
+                ErrorCode::UnknownEncoding,
+                format!("Unknown bytes encoding: {}", other),
+            )),
+        }
+    }
+
+    /// The `__cbor_bytes__` marker for a byte string
+    fn marker(self, bytes: &[u8]) -> serde_json::Map<String, JsonValue> {
+        let mut marker = serde_json::Map::new();
+        let (content, alphabet) = match self {
+            BytesEncoding::Hex => (json!(hex::encode(bytes)), None),
+            BytesEncoding::Base64 => (json!(BASE64.encode(bytes)), Some("base64")),
+            BytesEncoding::Base64Url => (json!(BASE64URL.encode(bytes)), Some("base64url")),
+            BytesEncoding::Array => (json!(bytes), None),
+        };
+        marker.insert("__cbor_bytes__".to_string(), content);
+        if let Some(alphabet) = alphabet {
+            marker.insert("__cbor_bytes_encoding__".to_string(), json!(alphabet));
+        }
+        marker
+    }
+}
+
+/// The bytes a `__cbor_bytes__` marker holds, in any form `BytesEncoding` writes
+///
+/// None if the content is not valid in its encoding, so the object is left a plain map.
+fn bytes_from_marker(obj: &serde_json::Map<String, JsonValue>) -> Option<Vec<u8>> {
+    let content = obj.get("__cbor_bytes__")?;
+    if let Some(items) = content.as_array() {
+        return items
+            .iter()
+            .map(|item| item.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+            .collect();
+    }
+    let text = content.as_str()?;
+    match obj.get("__cbor_bytes_encoding__").and_then(|v| v.as_str()) {
+        // "" decodes to an empty Vec, so the empty marker stays a byte string (0x40)
+        None | Some("hex") => hex::decode(text).ok(),
+        Some("base64") => BASE64.decode(text).ok(),
+        Some("base64url") => BASE64URL.decode(text).ok(),
+        Some(_) => None,
+    }
+}
+
+/// Markers for the tags whose content is a text string in a given format (RFC 8949 §3.4.5.3)
+const TEXT_TAG_MARKERS: [(&str, u64); 3] = [
+    ("__cbor_uri__", 32),
+    ("__cbor_b64url__", 33),
+    ("__cbor_b64__", 34),
+];
+
+/// A semantic conversion for a family of tags, enabled by name through "tag_handlers"
+pub struct TagHandler {
+    pub name: &'static str,
+    pub tags: &'static [u64],
+    /// Whether the content has the shape the conversion expects; if not, the generic
+    /// `__cbor_tag__` marker is used
+    pub accepts: fn(u64, &Value) -> bool,
+    pub convert: fn(u64, Value, &mut DecodeState) -> Result<JsonValue, JsonValue>,
+}
+
+/// Every tag handler, all enabled unless a request lists the ones it wants
+///
+/// ciborium already reads a tag 2/3 bignum that fits in 64 bits as a plain integer, so
+/// disabling "bignum" only keeps the tag on larger ones.
+pub const TAG_HANDLERS: [TagHandler; 8] = [
+    TagHandler {
+        name: "datetime",
+        tags: &[0, 1],
+        accepts: |tag, inner| match tag {
+            0 => inner.is_text(),
+            _ => inner.is_integer() || inner.is_float(),
+        },
+        convert: datetime_to_json,
+    },
+    TagHandler {
+        name: "bignum",
+        tags: &[2, 3],
+        accepts: |_, inner| inner.is_bytes(),
+        convert: bignum_to_json,
+    },
+    TagHandler {
+        name: "decimal",
+        tags: &[4],
+        accepts: |_, inner| is_exponent_mantissa(inner),
+        convert: exponent_mantissa_to_json,
+    },
+    TagHandler {
+        name: "bigfloat",
+        tags: &[5],
+        accepts: |_, inner| is_exponent_mantissa(inner),
+        convert: exponent_mantissa_to_json,
+    },
+    TagHandler {
+        name: "embedded",
+        tags: &[24],
+        accepts: |_, inner| inner.is_bytes(),
+        convert: embedded_to_json,
+    },
+    TagHandler {
+        name: "uri",
+        tags: &[32],
+        accepts: |_, _| true,
+        convert: text_tag_to_json,
+    },
+    TagHandler {
+        name: "base64url",
+        tags: &[33],
+        accepts: |_, _| true,
+        convert: text_tag_to_json,
+    },
+    TagHandler {
+        name: "base64",
+        tags: &[34],
+        accepts: |_, _| true,
+        convert: text_tag_to_json,
+    },
+];
+
+/// Read a "tag_handlers" request field: an array of TAG_HANDLERS names
+fn tag_handlers_from_request(names: &JsonValue) -> Result<Vec<&'static TagHandler>, JsonValue> {
+    let unknown = |name: String| {
+        let known: Vec<&str> = TAG_HANDLERS.iter().map(|handler| handler.name).collect();
+        error_response(
+            ErrorCode::UnknownTagHandler,
+            format!(
+                "Unknown tag handler: {} (expected some of {})",
+                name,
+                known.join(", ")
+            ),
+        )
+    };
+    let Some(names) = names.as_array() else {
+        return Err(unknown(names.to_string()));
+    };
+    names
+        .iter()
+        .map(|name| {
+            TAG_HANDLERS
+                .iter()
+                .find(|handler| name.as_str() == Some(handler.name))
+                .ok_or_else(|| unknown(name.as_str().map_or(name.to_string(), str::to_string)))
+        })
+        .collect()
+}
+
+/// Map key ordering used for canonical encoding
+#[derive(Clone, Copy)]
+pub enum CanonicalMode {
+    /// RFC 8949 §4.2.1: bytewise lexicographic order of the encoded keys
+    Bytewise,
+    /// RFC 7049 §3.9: shorter encoded keys first, equal lengths compared bytewise
+    LengthFirst,
+}
+
+impl CanonicalMode {
+    /// Descriptions of each mode, as listed by /info
+    pub const DESCRIPTIONS: [(&'static str, &'static str); 2] = [
+        (
+            "bytewise",
+            "RFC 8949 deterministic order: keys sorted by the bytewise order of their encoding",
+        ),
+        (
+            "length_first",
+            "RFC 7049 canonical order: shorter encoded keys first, then bytewise",
+        ),
+    ];
+
+    /// Read the optional "canonical_mode" request field, defaulting to bytewise
+    pub fn from_request(json: &JsonValue) -> Result<Self, JsonValue> {
+        match json.get("canonical_mode").and_then(|v| v.as_str()) {
+            None | Some("bytewise") => Ok(CanonicalMode::Bytewise),
+            Some("length_first") => Ok(CanonicalMode::LengthFirst),
+            Some(other) => Err(error_response(
+                ErrorCode::UnknownCanonicalMode,
+                format!("Unknown canonical mode: {}", other),
+            )),
+        }
+    }
+
+    /// Sort key for an encoded map key under this ordering
+    fn sort_key(self, key_bytes: Vec<u8>) -> (usize, Vec<u8>) {
+        match self {
+            CanonicalMode::Bytewise => (0, key_bytes),
+            CanonicalMode::LengthFirst => (key_bytes.len(), key_bytes),
+        }
+    }
+}
+
+/// Read the optional "depth_limit" request field, capped at MAX_DEPTH
+fn depth_limit_from_request(json: &JsonValue) -> usize {
+    json.get("depth_limit")
+        .and_then(|v| v.as_u64())
+        .map_or(MAX_DEPTH, |limit| (limit as usize).min(MAX_DEPTH))
+}
+
+/// Decode options plus wire-level facts that ciborium's Value discards, consumed in order by cbor_to_json
+pub struct DecodeState {
+    options: DecodeOptions,
+    /// For each item that ciborium reads as Value::Null, in wire order, the simple value it
+    /// really was: 22 for null, 23 for undefined, anything else was masked by mask_simple_values
+    null_origins: VecDeque<u8>,
+    /// For each Value::Float, in wire order, the width in bits it was encoded with and its raw
+    /// bits; ciborium widens every float to f64, which also drops NaN payloads
+    floats: VecDeque<(u8, u64)>,
+    /// Nesting depth of the item, which embedded tag 24 items count against the depth limit
+    depth: usize,
+    /// Information the conversion dropped, collected when the warnings option is set
+    warnings: Vec<String>,
+}
+
+impl DecodeState {
+    /// Collect the wire-level facts from the raw bytes of the first item
+    pub fn scan(bytes: &[u8], options: DecodeOptions) -> Self {
+        let mut null_origins = VecDeque::new();
+        let mut floats = VecDeque::new();
+        let mut deepest = 0;
+        // ciborium already accepted these bytes, so a scan failure only loses the annotations
+        let _ = scan_headers(bytes, &mut |header, depth| {
+            if let Some(simple) = null_like_simple(header) {
+                null_origins.push_back(simple);
+            }
+            if let Some(width) = float_width(header) {
+                floats.push_back((width, header.arg.unwrap_or_default()));
+            }
+            if matches!(header.major, 4..=6) {
+                deepest = deepest.max(depth + 1);
+            }
+        });
+        DecodeState {
+            options,
+            null_origins,
+            floats,
+            depth: deepest,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Record a lossy conversion if the caller asked for warnings
+    fn warn(&mut self, message: impl FnOnce() -> String) {
+        if self.options.warnings {
+            self.warnings.push(message());
+        }
+    }
+
+    /// Take the original simple value behind the next Value::Null in traversal order
+    fn next_null_origin(&mut self) -> u8 {
+        self.null_origins.pop_front().unwrap_or(SIMPLE_NULL)
+    }
+
+    /// Take the wire width and raw bits of the next Value::Float in traversal order
+    fn next_float(&mut self) -> (u8, u64) {
+        self.floats.pop_front().unwrap_or((64, f64::NAN.to_bits()))
+    }
+
+    /// Consume the wire facts of every Value::Null and Value::Float inside a value that is not walked
+    fn skip(&mut self, value: &Value) {
+        match value {
+            Value::Null => {
+                self.null_origins.pop_front();
+            }
+            Value::Float(_) => {
+                self.floats.pop_front();
+            }
+            Value::Array(arr) => arr.iter().for_each(|v| self.skip(v)),
+            Value::Map(map) => map.iter().for_each(|(k, v)| {
+                self.skip(k);
+                self.skip(v);
+            }),
+            Value::Tag(_, inner) => self.skip(inner),
+            _ => {}
+        }
+    }
+}
+
+/// The simple value behind each Value::Null of an item, keyed by the node's address
+///
+/// For walks that do not visit the nodes once in wire order, which DecodeState's queue
+/// assumes. The Value must stay where it is, unchanged, while this is in use.
+#[derive(Default)]
+struct NullOrigins(HashMap<*const Value, u8>);
+
+impl NullOrigins {
+    /// Take the null origins of every Value::Null in `value` off `state`
+    fn collect(value: &Value, state: &mut DecodeState) -> Self {
+        fn walk(value: &Value, state: &mut DecodeState, origins: &mut HashMap<*const Value, u8>) {
+            match value {
+                Value::Null => {
+                    origins.insert(value, state.next_null_origin());
+                }
+                Value::Float(_) => {
+                    state.next_float();
+                }
+                Value::Array(arr) => arr.iter().for_each(|v| walk(v, state, origins)),
+                Value::Map(map) => map.iter().for_each(|(k, v)| {
+                    walk(k, state, origins);
+                    walk(v, state, origins);
+                }),
+                Value::Tag(_, inner) => walk(inner, state, origins),
+                _ => {}
+            }
+        }
+        let mut origins = HashMap::new();
+        walk(value, state, &mut origins);
+        NullOrigins(origins)
+    }
+
+    /// The simple value a Value::Null node was on the wire
+    fn of(&self, value: &Value) -> u8 {
+        self.0
+            .get(&(value as *const Value))
+            .copied()
+            .unwrap_or(SIMPLE_NULL)
+    }
+}
+
+/// Convert CBOR Value to JSON-safe format with type markers
+///
+/// `state` must come from DecodeState::scan over the bytes `value` was read from;
+/// decode_cbor_bytes does the reading, scanning and converting in one call.
+pub fn cbor_to_json(mut value: Value, state: &mut DecodeState) -> Result<JsonValue, JsonValue> {
+    // Items past the limit are cut off here and skipped after the kept ones, in wire order
+    let dropped = match (&mut value, state.options.limit) {
+        (Value::Array(items), Some(limit)) if items.len() > limit => {
+            Some(Value::Array(items.split_off(limit)))
+        }
+        (Value::Map(entries), Some(limit)) if entries.len() > limit => {
+            Some(Value::Map(entries.split_off(limit)))
+        }
+        _ => None,
+    };
+
+    let mut converted = match value {
+        Value::Integer(i) => {
+            let num = i128::from(i);
+            // Check if it fits in the consumer's safe integer range (JavaScript's by default)
+            let safe = num.unsigned_abs() <= state.options.max_safe_int as u128
+                || (state.options.preserve_u64 && num >= 0);
+            // JSON numbers stop at the i64/u64 range, below which CBOR still goes to -2^64
+            match (i64::try_from(num), u64::try_from(num)) {
+                (Ok(n), _) if safe => json!(n),
+                (_, Ok(n)) if safe => json!(n),
+                _ => json!(num.to_string()),
+            }
+        }
+        Value::Bytes(bytes) => {
+            let mut marker = state.options.bytes_encoding.marker(&bytes);
+            if state.options.verbose_bytes {
+                marker.insert("__cbor_bytes_len__".to_string(), json!(bytes.len()));
+            }
+            JsonValue::Object(marker)
+        }
+        // Floats are visited in wire order, the same order scan() recorded them in
+        Value::Float(f)
+            if state.options.float_subtype || state.options.nan_bits || state.options.warnings =>
+        {
+            let (width, bits) = state.next_float();
+            let digits = usize::from(width / 4);
+            let mut converted = float_to_json(f, state.options.float_as_string);
+            if state.options.nan_bits && f.is_nan() {
+                converted["__cbor_nan_bits__"] = json!(format!("{:0digits$x}", bits));
+            } else if f.is_nan() && (width, bits) != (16, 0x7e00) {
+                state.warn(|| format!("NaN payload {:0digits$x} dropped", bits));
+            }
+            if state.options.float_subtype {
+                converted = json!({ "__cbor_float_width__": width, "value": converted });
+            } else if !f.is_nan() && width > shortest_float_width(f) {
+                state.warn(|| format!("{}-bit float {} will re-encode narrower", width, f));
+            }
+            converted
+        }
+        Value::Float(f) => float_to_json(f, state.options.float_as_string),
+        Value::Text(s) => json!(s),
+        Value::Bool(b) => json!(b),
+        Value::Null => {
+            // ciborium reads undefined (and masked simple values) as Null; the wire scan tells them apart
+            match state.next_null_origin() {
+                SIMPLE_NULL => json!(null),
+                SIMPLE_UNDEFINED => json!({"__cbor_undefined__": true}),
+                simple => json!({"__cbor_simple__": simple}),
+            }
+        }
+        Value::Array(arr) => {
+            let converted = arr
+                .into_iter()
+                .map(|v| cbor_to_json(v, state))
+                .collect::<Result<Vec<JsonValue>, JsonValue>>()?;
+            json!(converted)
+        }
+        Value::Map(map) if state.options.ordered => {
+            json!({ "__cbor_ordered_map__": map_to_pairs(map, state)? })
+        }
+        Value::Map(map)
+            if state.options.maps_as_pairs
+                || (state.options.preserve_int_keys && map.iter().any(|(k, _)| k.is_integer())) =>
+        {
+            // Keep integer keys as numbers instead of colliding with their string forms
+            json!({ "__cbor_map_entries__": map_to_pairs(map, state)? })
+        }
+        Value::Map(map) => {
+            // Sorted explicitly so key order never depends on serde_json's preserve_order feature
+            let mut result = BTreeMap::new();
+            for (k, v) in map {
+                state.skip(&k);
+                let (key, kind) = match k {
+                    Value::Text(s) => (s, None),
+                    Value::Integer(i) => (i128::from(i).to_string(), Some("integer")),
+                    Value::Bytes(b) => (hex::encode(&b), Some("byte string")),
+                    _ => (format!("{:?}", k), Some("non-text")),
+                };
+                if let Some(kind) = kind {
+                    state.warn(|| format!("{} map key '{}' stringified", kind, key));
+                }
+                if result.contains_key(&key) {
+                    if state.options.strict_maps {
+                        return Err(error_response(
+                            ErrorCode::DuplicateKey,
+                            format!("duplicate map key: {}", key),
+                        ));
+                    }
+                    state.warn(|| format!("duplicate map key '{}' overwritten", key));
+                }
+                let converted = cbor_to_json(v, state)?;
+                result.insert(key, converted);
+            }
+            JsonValue::Object(result.into_iter().collect())
+        }
+        Value::Tag(tag, inner) => {
+            let handler = state
+                .options
+                .tag_handlers
+                .iter()
+                .find(|handler| handler.tags.contains(&tag) && (handler.accepts)(tag, &inner));
+            match handler {
+                Some(handler) => (handler.convert)(tag, *inner, state)?,
+                None => json!({
+                    "__cbor_tag__": tag,
+                    "__cbor_value__": cbor_to_json(*inner, state)?
+                }),
+            }
+        }
+        _ => json!(null),
+    };
+
+    if let Some(dropped) = dropped {
+        state.skip(&dropped);
+        match (dropped, &mut converted) {
+            (Value::Array(rest), JsonValue::Array(items)) => {
+                items.push(json!({ "__cbor_truncated__": true, "remaining": rest.len() }));
+            }
+            (Value::Map(rest), JsonValue::Object(members)) => {
+                members.insert("__cbor_truncated__".to_string(), json!(true));
+                members.insert("__cbor_remaining__".to_string(), json!(rest.len()));
+            }
+            _ => {}
+        }
+    }
+    Ok(converted)
+}
+
+/// Standard date/time (tag 0) and epoch-based date/time (tag 1)
+fn datetime_to_json(
+    tag: u64,
+    inner: Value,
+    state: &mut DecodeState,
+) -> Result<JsonValue, JsonValue> {
+    Ok(match (tag, inner) {
+        (0, Value::Text(datetime)) => json!({ "__cbor_datetime__": datetime }),
+        (_, epoch) => json!({ "__cbor_epoch__": cbor_to_json(epoch, state)? }),
+    })
+}
+
+/// Bignums (tags 2/3) that overflow Integer keep their tag; render them as decimal
+fn bignum_to_json(tag: u64, inner: Value, _: &mut DecodeState) -> Result<JsonValue, JsonValue> {
+    let mut magnitude = inner.into_bytes().unwrap_or_default();
+    Ok(if tag == 2 {
+        json!({ "__cbor_bignum__": bytes_to_decimal(&magnitude) })
+    } else {
+        // Tag 3 encodes -1 - n
+        increment_bytes(&mut magnitude);
+        json!({ "__cbor_bignum__": format!("-{}", bytes_to_decimal(&magnitude)) })
+    })
+}
+
+/// Decimal fractions (tag 4) and bigfloats (tag 5): [exponent, mantissa]
+fn exponent_mantissa_to_json(
+    tag: u64,
+    inner: Value,
+    state: &mut DecodeState,
+) -> Result<JsonValue, JsonValue> {
+    let Value::Array(mut parts) = inner else {
+        unreachable!("checked by is_exponent_mantissa")
+    };
+    let mantissa = parts.pop().unwrap_or(Value::Null);
+    let exponent = parts.pop().unwrap_or(Value::Null);
+    let pair = json!({
+        "exp": cbor_to_json(exponent, state)?,
+        "mantissa": cbor_to_json(mantissa, state)?
+    });
+    Ok(if tag == 4 {
+        json!({ "__cbor_decimal__": pair })
+    } else {
+        json!({ "__cbor_bigfloat__": pair })
+    })
+}
+
+/// Encoded CBOR data item (tag 24), decoded in place when the decode_embedded option is set
+fn embedded_to_json(
+    tag: u64,
+    inner: Value,
+    state: &mut DecodeState,
+) -> Result<JsonValue, JsonValue> {
+    let embedded = inner.into_bytes().unwrap_or_default();
+    let decoded = if state.options.decode_embedded {
+        decode_embedded(&embedded, state)?
+    } else {
+        None
+    };
+    Ok(match decoded {
+        Some(decoded) => json!({ "__cbor_encoded_cbor__": decoded }),
+        None => json!({
+            "__cbor_tag__": tag,
+            "__cbor_value__": cbor_to_json(Value::Bytes(embedded), state)?
+        }),
+    })
+}
+
+/// URI (tag 32), base64url (tag 33) and base64 (tag 34) text
+fn text_tag_to_json(tag: u64, inner: Value, _: &mut DecodeState) -> Result<JsonValue, JsonValue> {
+    let (marker, _) = TEXT_TAG_MARKERS[(tag - 32) as usize];
+    match inner {
+        Value::Text(text) => Ok(json!({ marker: text })),
+        _ => Err(error_response(
+            ErrorCode::InvalidTagContent,
+            format!("tag {} content must be a text string", tag),
+        )),
+    }
+}
+
+/// Convert map entries to `[key, value]` pairs in wire order, keys keeping their own types
+fn map_to_pairs(
+    map: Vec<(Value, Value)>,
+    state: &mut DecodeState,
+) -> Result<Vec<JsonValue>, JsonValue> {
+    map.into_iter()
+        .map(|(k, v)| Ok(json!([cbor_to_json(k, state)?, cbor_to_json(v, state)?])))
+        .collect()
+}
+
+/// Whether a tag 4/5 content is the `[exponent, mantissa]` pair RFC 8949 §3.4.4 defines,
+/// with an integer exponent and an integer or bignum mantissa
+fn is_exponent_mantissa(value: &Value) -> bool {
+    match value.as_array().map(Vec::as_slice) {
+        Some([Value::Integer(_), Value::Integer(_)]) => true,
+        Some([Value::Integer(_), Value::Tag(2 | 3, mantissa)]) => mantissa.is_bytes(),
+        _ => false,
+    }
+}
+
+/// A float as a JSON number, or a `__cbor_float__` marker for values JSON cannot carry
+///
+/// With `as_string`, finite floats are markers too, holding Rust's shortest round-trip
+/// decimal (`0.1`, `1e300`, `2.0`), so the text does not depend on the JSON serializer.
+fn float_to_json(f: f64, as_string: bool) -> JsonValue {
+    if f.is_nan() {
+        json!({"__cbor_float__": "NaN"})
+    } else if f.is_infinite() {
+        if f.is_sign_positive() {
+            json!({"__cbor_float__": "Infinity"})
+        } else {
+            json!({"__cbor_float__": "-Infinity"})
+        }
+    } else if f == 0.0 && f.is_sign_negative() {
+        // JSON consumers commonly drop the sign of zero, so spell it out
+        json!({"__cbor_float__": "-0"})
+    } else if as_string {
+        json!({"__cbor_float__": format!("{:?}", f)})
+    } else {
+        json!(f)
+    }
+}
+
+/// The value of a `__cbor_float__` marker given as a number or as a decimal string
+///
+/// The spelled-out markers ("NaN", "Infinity", "-Infinity", "-0") are not decimals and give None.
+fn float_marker_value(marker: &JsonValue) -> Option<f64> {
+    match marker {
+        JsonValue::String(s)
+            if s != "-0"
+                && s.trim_start_matches('-')
+                    .starts_with(|c: char| c.is_ascii_digit()) =>
+        {
+            s.parse().ok()
+        }
+        other => other.as_f64(),
+    }
+}
+
+/// Decode the content of a tag 24 byte string, or `None` if it is not exactly one well-formed item
+///
+/// The embedded item inherits the decode options, and its nesting counts on top of the
+/// enclosing item's against the depth limit, so chains of tag 24 stay bounded.
+fn decode_embedded(bytes: &[u8], state: &mut DecodeState) -> Result<Option<JsonValue>, JsonValue> {
+    let mut options = state.options.clone();
+    options.depth_limit = options.depth_limit.saturating_sub(state.depth);
+    check_depth(bytes, options.depth_limit)?;
+
+    let value = match read_item(bytes, &mask_simple_values(bytes)) {
+        Ok((v, len)) if len == bytes.len() => v,
+        _ => return Ok(None),
+    };
+
+    let mut embedded_state = DecodeState::scan(bytes, options);
+    let converted = cbor_to_json(value, &mut embedded_state)?;
+    state.warnings.append(&mut embedded_state.warnings);
+    Ok(Some(converted))
+}
+
+/// Convert the root item for the cose option, unwrapping byte strings that hold one CBOR item
+///
+/// Only the elements of a top-level array are tried, so a signature or ciphertext that happens
+/// to parse is left alone deeper down. Empty byte strings (an absent protected header) and byte
+/// strings that are not exactly one well-formed item stay `__cbor_bytes__`.
+fn cose_to_json(value: Value, state: &mut DecodeState) -> Result<JsonValue, JsonValue> {
+    match value {
+        Value::Tag(tag, inner) if inner.is_array() => Ok(json!({
+            "__cbor_tag__": tag,
+            "__cbor_value__": cose_to_json(*inner, state)?
+        })),
+        Value::Array(items) => {
+            let mut converted = Vec::with_capacity(items.len());
+            for item in items {
+                let decoded = match &item {
+                    Value::Bytes(bytes) if !bytes.is_empty() => decode_embedded(bytes, state)?,
+                    _ => None,
+                };
+                converted.push(match decoded {
+                    Some(decoded) => json!({ "__cbor_bstr_cbor__": decoded }),
+                    None => cbor_to_json(item, state)?,
+                });
+            }
+            Ok(json!(converted))
+        }
+        other => cbor_to_json(other, state),
+    }
+}
+
+/// Nesting depth of arrays and maps in a Value (0 for scalars, tags are transparent)
+///
+/// Recursion is bounded because ciborium refuses inputs nested deeper than 256 levels.
+fn value_depth(value: &Value) -> usize {
+    match value {
+        Value::Array(arr) => 1 + arr.iter().map(value_depth).max().unwrap_or(0),
+        Value::Map(map) => {
+            1 + map
+                .iter()
+                .map(|(k, v)| value_depth(k).max(value_depth(v)))
+                .max()
+                .unwrap_or(0)
+        }
+        Value::Tag(_, inner) => value_depth(inner),
+        _ => 0,
+    }
+}
+
+/// Short name of a Value's major type, as spelled in type_histogram
+fn major_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Integer(i) if i128::from(*i) < 0 => "nint",
+        Value::Integer(_) => "uint",
+        Value::Bytes(_) => "bstr",
+        Value::Text(_) => "tstr",
+        Value::Array(_) => "array",
+        Value::Map(_) => "map",
+        Value::Tag(..) => "tag",
+        _ => "simple",
+    }
+}
+
+/// Count the items of each major type in a Value, keys and tag contents included
+///
+/// Major type 7 is counted as "simple" whether the item is a float, a boolean or null.
+fn type_histogram(value: &Value, histogram: &mut BTreeMap<&'static str, u64>) {
+    *histogram.entry(major_type_name(value)).or_default() += 1;
+    match value {
+        Value::Array(arr) => arr.iter().for_each(|v| type_histogram(v, histogram)),
+        Value::Map(map) => map.iter().for_each(|(k, v)| {
+            type_histogram(k, histogram);
+            type_histogram(v, histogram);
+        }),
+        Value::Tag(_, inner) => type_histogram(inner, histogram),
+        _ => {}
+    }
+}
+
+/// Semantic equality of two CBOR values: map order is ignored and NaN equals NaN
+fn cbor_values_equal(a: &Value, b: &Value, nulls: [&NullOrigins; 2]) -> bool {
+    first_difference(a, b, "root", nulls).is_none()
+}
+
+/// Describe the first place two values diverge, e.g. `root.items[2].name: 1 != 2`
+///
+/// `nulls` holds what the nulls of `a` and `b` were on the wire, so undefined and other
+/// simple values only equal themselves.
+fn first_difference(a: &Value, b: &Value, path: &str, nulls: [&NullOrigins; 2]) -> Option<String> {
+    match (a, b) {
+        (Value::Float(x), Value::Float(y)) => {
+            if x == y || (x.is_nan() && y.is_nan()) {
+                None
+            } else {
+                Some(format!(
+                    "{}: {} != {}",
+                    path,
+                    diag_float(*x),
+                    diag_float(*y)
+                ))
+            }
+        }
+        (Value::Array(xs), Value::Array(ys)) => {
+            if xs.len() != ys.len() {
+                return Some(format!(
+                    "{}: array length {} != {}",
+                    path,
+                    xs.len(),
+                    ys.len()
+                ));
+            }
+            xs.iter()
+                .zip(ys)
+                .enumerate()
+                .find_map(|(i, (x, y))| first_difference(x, y, &format!("{}[{}]", path, i), nulls))
+        }
+        (Value::Map(xs), Value::Map(ys)) => {
+            if xs.len() != ys.len() {
+                return Some(format!("{}: map size {} != {}", path, xs.len(), ys.len()));
+            }
+            xs.iter().find_map(|(key, x)| {
+                let entry_path = match key {
+                    Value::Text(k) => format!("{}.{}", path, k),
+                    other => format!("{}[{}]", path, diag_value(other, nulls[0])),
+                };
+                match ys
+                    .iter()
+                    .find(|(other, _)| cbor_values_equal(key, other, nulls))
+                {
+                    Some((_, y)) => first_difference(x, y, &entry_path, nulls),
+                    None => Some(format!("{}: key missing from b", entry_path)),
+                }
+            })
+        }
+        (Value::Tag(x_tag, x), Value::Tag(y_tag, y)) => {
+            if x_tag != y_tag {
+                return Some(format!("{}: tag {} != {}", path, x_tag, y_tag));
+            }
+            first_difference(x, y, path, nulls)
+        }
+        _ => {
+            if a == b && (*a != Value::Null || nulls[0].of(a) == nulls[1].of(b)) {
+                None
+            } else {
+                Some(format!(
+                    "{}: {} != {}",
+                    path,
+                    diag_value(a, nulls[0]),
+                    diag_value(b, nulls[1])
+                ))
+            }
+        }
+    }
+}
+
+/// Render a big-endian unsigned integer of any length as a decimal string
+fn bytes_to_decimal(bytes: &[u8]) -> String {
+    // Little-endian base 10 digits, grown as the value is shifted in byte by byte
+    let mut digits: Vec<u8> = vec![0];
+    for &byte in bytes {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            let acc = *digit as u32 * 256 + carry;
+            *digit = (acc % 10) as u8;
+            carry = acc / 10;
+        }
+        while carry > 0 {
+            digits.push((carry % 10) as u8);
+            carry /= 10;
+        }
+    }
+    digits.iter().rev().map(|d| char::from(b'0' + d)).collect()
+}
+
+/// Parse a decimal string of any length into minimal big-endian bytes
+fn decimal_to_bytes(decimal: &str) -> Option<Vec<u8>> {
+    if decimal.is_empty() || !decimal.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    // Little-endian base 256 limbs
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in decimal.bytes() {
+        let mut carry = (c - b'0') as u32;
+        for byte in bytes.iter_mut() {
+            let acc = *byte as u32 * 10 + carry;
+            *byte = (acc & 0xff) as u8;
+            carry = acc >> 8;
+        }
+        if carry > 0 {
+            bytes.push(carry as u8);
+        }
+    }
+    bytes.reverse();
+    Some(bytes)
+}
+
+/// Add one to a big-endian unsigned integer in place
+fn increment_bytes(bytes: &mut Vec<u8>) {
+    for byte in bytes.iter_mut().rev() {
+        let (sum, overflow) = byte.overflowing_add(1);
+        *byte = sum;
+        if !overflow {
+            return;
+        }
+    }
+    bytes.insert(0, 1);
+}
+
+/// Subtract one from a non-zero big-endian unsigned integer in place, keeping it minimal
+fn decrement_bytes(bytes: &mut Vec<u8>) {
+    for byte in bytes.iter_mut().rev() {
+        let (diff, underflow) = byte.overflowing_sub(1);
+        *byte = diff;
+        if !underflow {
+            break;
+        }
+    }
+    let leading_zeros = bytes.iter().take_while(|&&b| b == 0).count();
+    bytes.drain(..leading_zeros);
+}
+
+/// Build a tag 2/3 bignum from a decimal string like "-18446744073709551617"
+fn bignum_from_decimal(decimal: &str) -> Option<Value> {
+    match decimal.strip_prefix('-') {
+        Some(magnitude) => {
+            let mut bytes = decimal_to_bytes(magnitude)?;
+            if bytes.is_empty() {
+                // "-0" is just zero
+                return Some(Value::Tag(2, Box::new(Value::Bytes(bytes))));
+            }
+            decrement_bytes(&mut bytes);
+            Some(Value::Tag(3, Box::new(Value::Bytes(bytes))))
+        }
+        None => {
+            let bytes = decimal_to_bytes(decimal)?;
+            Some(Value::Tag(2, Box::new(Value::Bytes(bytes))))
+        }
+    }
+}
+
+/// Render a CBOR Value in RFC 8949 diagnostic notation
+///
+/// `state` must come from DecodeState::scan over the bytes `value` was read from, as for
+/// cbor_to_json; it tells `undefined` and `simple(N)` apart from the null ciborium reads.
+pub fn cbor_to_diag(value: Value, state: &mut DecodeState) -> String {
+    let nulls = NullOrigins::collect(&value, state);
+    diag_value(&value, &nulls)
+}
+
+/// Render a Value in diagnostic notation, looking up what each null was in `nulls`
+fn diag_value(value: &Value, nulls: &NullOrigins) -> String {
+    match value {
+        Value::Integer(i) => i128::from(*i).to_string(),
+        Value::Bytes(bytes) => format!("h'{}'", hex::encode(bytes)),
+        Value::Float(f) => diag_float(*f),
+        // JSON string escaping matches the diagnostic notation for text strings
+        Value::Text(s) => JsonValue::String(s.clone()).to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Null => diag_simple(nulls.of(value)),
+        Value::Array(arr) => {
+            let items: Vec<String> = arr.iter().map(|v| diag_value(v, nulls)).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Map(map) => {
+            let entries: Vec<String> = map
+                .iter()
+                .map(|(k, v)| format!("{}: {}", diag_value(k, nulls), diag_value(v, nulls)))
+                .collect();
+            format!("{{{}}}", entries.join(", "))
+        }
+        Value::Tag(tag, inner) => format!("{}({})", tag, diag_value(inner, nulls)),
+        _ => "null".to_string(),
+    }
+}
+
+/// Spell a null-like simple value: `null`, `undefined` or `simple(N)`
+fn diag_simple(simple: u8) -> String {
+    match simple {
+        SIMPLE_NULL => "null".to_string(),
+        SIMPLE_UNDEFINED => "undefined".to_string(),
+        other => format!("simple({})", other),
+    }
+}
+
+/// Format a float so it always reads as a float in diagnostic notation (e.g. `1.0`, `1.0e+300`)
+fn diag_float(f: f64) -> String {
+    if f.is_nan() {
+        return "NaN".to_string();
+    }
+    if f.is_infinite() {
+        return if f.is_sign_positive() {
+            "Infinity"
+        } else {
+            "-Infinity"
+        }
+        .to_string();
+    }
+
+    let repr = format!("{:?}", f);
+    match repr.split_once('e') {
+        Some((mantissa, exponent)) => {
+            let mantissa = if mantissa.contains('.') {
+                mantissa.to_string()
+            } else {
+                format!("{}.0", mantissa)
+            };
+            let sign = if exponent.starts_with('-') { "" } else { "+" };
+            format!("{}e{}{}", mantissa, sign, exponent)
+        }
+        None => repr,
+    }
+}
+
+/// Parse RFC 8949 diagnostic notation into a CBOR Value
+///
+/// Supports integers, floats (including NaN and ±Infinity), text strings, `h'...'`
+/// byte strings, arrays, maps, booleans, null, undefined, `simple(N)` and `N(...)` tags.
+/// Backs both /encode-diag and the CLI's from-diag action. Arrays, maps and tags nested more
+/// than MAX_DEPTH deep fail with `depth_exceeded`, like the same nesting in CBOR input, before
+/// the recursion can exhaust the stack.
+///
+/// Value has no undefined or other simple values, so those read as Value::Null here;
+/// encode_diag writes them as themselves.
+pub fn parse_diag(input: &str) -> Result<Value, JsonValue> {
+    parse_diag_simples(input).map(|(value, _)| value)
+}
+
+/// parse_diag, plus the simple value each Value::Null was written as, in source order
+fn parse_diag_simples(input: &str) -> Result<(Value, Vec<u8>), JsonValue> {
+    let mut parser = DiagParser {
+        input,
+        pos: 0,
+        depth: 0,
+        nulls: Vec::new(),
+    };
+    let parsed = parser.parse_value().and_then(|value| {
+        parser.skip_whitespace();
+        if parser.pos < input.len() {
+            return Err(format!(
+                "unexpected trailing input at {}",
+                parser.location(parser.pos)
+            ));
+        }
+        Ok((value, std::mem::take(&mut parser.nulls)))
+    });
+    parsed.map_err(|e| {
+        if parser.depth > MAX_DEPTH {
+            error_response(ErrorCode::DepthExceeded, "max depth exceeded")
+        } else {
+            error_response(
+                ErrorCode::InvalidDiagnostic,
+                format!("Invalid diagnostic notation: {}", e),
+            )
+        }
+    })
+}
+
+/// Recursive-descent parser state for parse_diag
+struct DiagParser<'a> {
+    input: &'a str,
+    pos: usize,
+    /// Arrays, maps and tags open at `pos`; left above MAX_DEPTH when parsing stopped there
+    depth: usize,
+    /// For each Value::Null parsed so far, the simple value it spells: 22 for null, 23 for
+    /// undefined, N for `simple(N)`
+    nulls: Vec<u8>,
+}
+
+impl<'a> DiagParser<'a> {
+    /// Line and column (both from 1, columns in characters) of a byte offset, for errors
+    fn location(&self, offset: usize) -> String {
+        let before = &self.input[..offset];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let column = before[line_start..].chars().count() + 1;
+        format!("line {}, column {}", line, column)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.input.len() - trimmed.len();
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            Ok(())
+        } else {
+            Err(format!("expected '{}' at {}", c, self.location(self.pos)))
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('[') => self.nested(Self::parse_array),
+            Some('{') => self.nested(Self::parse_map),
+            Some('"') => self.parse_text(),
+            Some('h') if self.rest().starts_with("h'") => self.parse_hex_bytes(),
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' => self.parse_number(),
+            Some(_) => self.parse_keyword(),
+            None => Err(format!(
+                "unexpected end of input at {}",
+                self.location(self.pos)
+            )),
+        }
+    }
+
+    /// Parse an array, map or tag content one level deeper, failing past MAX_DEPTH
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Value, String>) -> Result<Value, String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(format!("nesting too deep at {}", self.location(self.pos)));
+        }
+        let value = parse(self)?;
+        self.depth -= 1;
+        Ok(value)
+    }
+
+    fn parse_array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => {
+                    return Err(format!(
+                        "expected ',' or ']' at {}",
+                        self.location(self.pos)
+                    ))
+                }
+            }
+        }
+    }
+
+    fn parse_map(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut entries = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Map(entries));
+        }
+        loop {
+            let key = self.parse_value()?;
+            self.expect(':')?;
+            let value = self.parse_value()?;
+            entries.push((key, value));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Value::Map(entries));
+                }
+                _ => {
+                    return Err(format!(
+                        "expected ',' or '}}' at {}",
+                        self.location(self.pos)
+                    ))
+                }
+            }
+        }
+    }
+
+    fn parse_text(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        let mut escaped = false;
+        for (i, c) in self.rest().char_indices().skip(1) {
+            match c {
+                '\\' if !escaped => escaped = true,
+                '"' if !escaped => {
+                    let end = start + i + 1;
+                    // Diagnostic text strings use JSON escaping
+                    let text: String =
+                        serde_json::from_str(&self.input[start..end]).map_err(|e| {
+                            format!("invalid text string at {}: {}", self.location(start), e)
+                        })?;
+                    self.pos = end;
+                    return Ok(Value::Text(text));
+                }
+                _ => escaped = false,
+            }
+        }
+        Err(format!(
+            "unterminated text string at {}",
+            self.location(start)
+        ))
+    }
+
+    fn parse_hex_bytes(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        self.pos += 2;
+        let end = self
+            .rest()
+            .find('\'')
+            .ok_or_else(|| format!("unterminated byte string at {}", self.location(start)))?;
+        let digits: String = self.rest()[..end]
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        self.pos += end + 1;
+        hex::decode(&digits)
+            .map(Value::Bytes)
+            .map_err(|e| format!("invalid byte string at {}: {}", self.location(start), e))
+    }
+
+    fn parse_number(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        if self.rest().starts_with("-Infinity") {
+            self.pos += "-Infinity".len();
+            return Ok(Value::Float(f64::NEG_INFINITY));
+        }
+
+        let len = self
+            .rest()
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.')))
+            .unwrap_or(self.rest().len());
+        let token = &self.rest()[..len];
+        self.pos += len;
+
+        if token.contains(['.', 'e', 'E']) {
+            return token
+                .parse::<f64>()
+                .map(Value::Float)
+                .map_err(|_| format!("invalid number '{}' at {}", token, self.location(start)));
+        }
+
+        let number = token
+            .parse::<i128>()
+            .map_err(|_| format!("invalid number '{}' at {}", token, self.location(start)))?;
+
+        self.skip_whitespace();
+        if self.peek() == Some('(') {
+            let tag = u64::try_from(number).map_err(|_| {
+                format!("invalid tag number '{}' at {}", token, self.location(start))
+            })?;
+            self.pos += 1;
+            let inner = self.nested(Self::parse_value)?;
+            self.expect(')')?;
+            return Ok(Value::Tag(tag, Box::new(inner)));
+        }
+
+        ciborium::value::Integer::try_from(number)
+            .map(Value::Integer)
+            .map_err(|_| {
+                format!(
+                    "integer '{}' out of CBOR range at {}",
+                    token,
+                    self.location(start)
+                )
+            })
+    }
+
+    fn parse_keyword(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        let len = self
+            .rest()
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(self.rest().len());
+        let word = &self.rest()[..len];
+        let value = match word {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            "null" => self.simple(SIMPLE_NULL),
+            "undefined" => self.simple(SIMPLE_UNDEFINED),
+            "simple" => {
+                self.pos += len;
+                return self.parse_simple(start);
+            }
+            "NaN" => Value::Float(f64::NAN),
+            "Infinity" => Value::Float(f64::INFINITY),
+            _ => return Err(format!("unexpected token at {}", self.location(start))),
+        };
+        self.pos += len;
+        Ok(value)
+    }
+
+    /// Parse the `(N)` of `simple(N)`; 20 and 21 are false and true, 24..=31 are reserved
+    fn parse_simple(&mut self, start: usize) -> Result<Value, String> {
+        self.expect('(')?;
+        self.skip_whitespace();
+        let len = self
+            .rest()
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest().len());
+        let simple = match self.rest()[..len].parse::<u8>() {
+            Ok(simple) if !(24..=31).contains(&simple) => simple,
+            _ => return Err(format!("invalid simple value at {}", self.location(start))),
+        };
+        self.pos += len;
+        self.expect(')')?;
+        Ok(match simple {
+            20 => Value::Bool(false),
+            21 => Value::Bool(true),
+            other => self.simple(other),
+        })
+    }
+
+    /// A Value::Null standing for `simple`, recorded so encode_diag can write it back
+    fn simple(&mut self, simple: u8) -> Value {
+        self.nulls.push(simple);
+        Value::Null
+    }
+}
+
+/// Parse a JSON string holding an integer CBOR can carry natively (-2^64..2^64-1)
+fn integer_from_string(s: &str) -> Option<ciborium::value::Integer> {
+    s.parse::<i128>()
+        .ok()
+        .and_then(|n| ciborium::value::Integer::try_from(n).ok())
+}
+
+/// Convert JSON value to CBOR Value
+///
+/// A JSON string only becomes a byte string through `__cbor_bytes__`, never by looking like
+/// hex. Strings holding an integer do become integers, so `{"__cbor_text__": "12"}` is the
+/// way to keep such a string (or any string a later rule might reinterpret) as text.
+pub fn json_to_cbor(value: JsonValue) -> Value {
+    json_to_cbor_with(value, true)
+}
+
+/// Convert JSON value to CBOR Value, reading integer strings as integers only if asked
+///
+/// With `integer_strings` off every JSON string stays text, so decoded text re-encodes to
+/// the same bytes; `__cbor_int__` values and `__cbor_int_map__` keys still parse theirs.
+fn json_to_cbor_with(value: JsonValue, integer_strings: bool) -> Value {
+    match value {
+        JsonValue::Null => Value::Null,
+        JsonValue::Bool(b) => Value::Bool(b),
+        JsonValue::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Value::Integer(i.into())
+            } else if let Some(u) = n.as_u64() {
+                Value::Integer(u.into())
+            } else if let Some(f) = n.as_f64() {
+                Value::Float(f)
+            } else {
+                Value::Null
+            }
+        }
+        JsonValue::String(s) => {
+            // Check if it's a large integer string; anything out of range stays text
+            match integer_from_string(&s).filter(|_| integer_strings) {
+                Some(integer) => Value::Integer(integer),
+                None => Value::Text(s),
+            }
+        }
+        JsonValue::Array(arr) => {
+            let converted: Vec<Value> = arr
+                .into_iter()
+                .map(|item| json_to_cbor_with(item, integer_strings))
+                .collect();
+            Value::Array(converted)
+        }
+        JsonValue::Object(obj) => {
+            // Check for special markers
+            if let Some(bytes) = bytes_from_marker(&obj) {
+                return Value::Bytes(bytes);
+            }
+
+            if let Some(JsonValue::String(text)) = obj.get("__cbor_text__") {
+                return Value::Text(text.clone());
+            }
+
+            if let Some(decimal) = obj.get("__cbor_bignum__").and_then(|v| v.as_str()) {
+                if let Some(bignum) = bignum_from_decimal(decimal) {
+                    return bignum;
+                }
+            }
+
+            if let Some(float_str) = obj.get("__cbor_float__") {
+                // A number forces float encoding even when it is whole, e.g. 2 as 2.0
+                if let Some(f) = float_marker_value(float_str) {
+                    return Value::Float(f);
+                }
+                if let Some(s) = float_str.as_str() {
+                    return match s {
+                        "NaN" => match obj.get("__cbor_nan_bits__").and_then(nan_from_bits) {
+                            Some(encoded) => ciborium::de::from_reader(&encoded[..])
+                                .unwrap_or(Value::Float(f64::NAN)),
+                            None => Value::Float(f64::NAN),
+                        },
+                        "Infinity" => Value::Float(f64::INFINITY),
+                        "-Infinity" => Value::Float(f64::NEG_INFINITY),
+                        "-0" => Value::Float(-0.0),
+                        _ => Value::Null,
+                    };
+                }
+            }
+
+            for (marker, tag) in TEXT_TAG_MARKERS {
+                if let Some(text) = obj.get(marker).and_then(|v| v.as_str()) {
+                    return Value::Tag(tag, Box::new(Value::Text(text.to_string())));
+                }
+            }
+
+            if let Some(datetime) = obj.get("__cbor_datetime__").and_then(|v| v.as_str()) {
+                return Value::Tag(0, Box::new(Value::Text(datetime.to_string())));
+            }
+
+            // A float epoch stays a float, so fractional seconds survive the round trip
+            if let Some(epoch) = obj.get("__cbor_epoch__").filter(|v| !v.is_null()) {
+                return Value::Tag(
+                    1,
+                    Box::new(json_to_cbor_with(epoch.clone(), integer_strings)),
+                );
+            }
+
+            for (marker, tag) in [("__cbor_decimal__", 4), ("__cbor_bigfloat__", 5)] {
+                if let Some(pair) = obj.get(marker) {
+                    if let (Some(exp), Some(mantissa)) = (pair.get("exp"), pair.get("mantissa")) {
+                        let parts = vec![
+                            json_to_cbor_with(exp.clone(), integer_strings),
+                            json_to_cbor_with(mantissa.clone(), integer_strings),
+                        ];
+                        return Value::Tag(tag, Box::new(Value::Array(parts)));
+                    }
+                }
+            }
+
+            // Value only has shortest-form floats; write_json_cbor honors the width
+            if let (Some(_), Some(inner)) = (obj.get("__cbor_float_width__"), obj.get("value")) {
+                return json_to_cbor_with(inner.clone(), integer_strings);
+            }
+
+            if let Some(inner) = obj.get("__cbor_encoded_cbor__") {
+                let mut embedded = Vec::new();
+                if ciborium::into_writer(
+                    &json_to_cbor_with(inner.clone(), integer_strings),
+                    &mut embedded,
+                )
+                .is_ok()
+                {
+                    return Value::Tag(24, Box::new(Value::Bytes(embedded)));
+                }
+            }
+
+            if let Some(inner) = obj.get("__cbor_bstr_cbor__") {
+                let mut embedded = Vec::new();
+                if ciborium::into_writer(
+                    &json_to_cbor_with(inner.clone(), integer_strings),
+                    &mut embedded,
+                )
+                .is_ok()
+                {
+                    return Value::Bytes(embedded);
+                }
+            }
+
+            if let (Some(tag), Some(inner)) = (obj.get("__cbor_tag__"), obj.get("__cbor_value__")) {
+                if let Some(tag_num) = tag.as_u64() {
+                    return Value::Tag(
+                        tag_num,
+                        Box::new(json_to_cbor_with(inner.clone(), integer_strings)),
+                    );
+                }
+            }
+
+            // Value always writes the shortest argument; write_json_cbor honours __cbor_int_width__
+            if let Some(int) = obj.get("__cbor_int__") {
+                if let Value::Integer(integer) = json_to_cbor(int.clone()) {
+                    return Value::Integer(integer);
+                }
+            }
+
+            if obj.contains_key("__cbor_undefined__") {
+                return Value::Null; // ciborium doesn't have undefined; write_json_cbor emits 0xf7
+            }
+
+            if obj.contains_key("__cbor_simple__") {
+                return Value::Null; // nor other simple values; write_json_cbor emits those too
+            }
+
+            // Value has no indefinite-length form, so these collapse to definite containers here;
+            // write_json_cbor emits the real indefinite encoding
+            if let Some(JsonValue::Array(items)) = obj.get("__cbor_indefinite_array__") {
+                return Value::Array(
+                    items
+                        .iter()
+                        .map(|item| json_to_cbor_with(item.clone(), integer_strings))
+                        .collect(),
+                );
+            }
+
+            if let Some(chunks) = obj.get("__cbor_indefinite_bytes__").and_then(bytes_chunks) {
+                return Value::Bytes(chunks.concat());
+            }
+
+            if let Some(chunks) = obj.get("__cbor_indefinite_text__").and_then(text_chunks) {
+                return Value::Text(chunks.concat());
+            }
+
+            if let Some(entries) = pairs_marker(&obj) {
+                let map = entries
+                    .into_iter()
+                    .map(|(k, v)| {
+                        (
+                            entry_key_to_cbor(k),
+                            json_to_cbor_with(v.clone(), integer_strings),
+                        )
+                    })
+                    .collect();
+                return Value::Map(map);
+            }
+
+            // Checked after __cbor_map_entries__, which wins if an object has both
+            if let Some(JsonValue::Object(entries)) = obj.get("__cbor_int_map__") {
+                let map = entries
+                    .iter()
+                    .map(|(k, v)| {
+                        (
+                            int_map_key(k),
+                            json_to_cbor_with(v.clone(), integer_strings),
+                        )
+                    })
+                    .collect();
+                return Value::Map(map);
+            }
+
+            if let Some(JsonValue::Object(entries)) = obj.get("__cbor_indefinite_map__") {
+                let map = entries
+                    .iter()
+                    .map(|(k, v)| {
+                        (
+                            Value::Text(k.clone()),
+                            json_to_cbor_with(v.clone(), integer_strings),
+                        )
+                    })
+                    .collect();
+                return Value::Map(map);
+            }
+
+            // Regular map
+            let mut map = Vec::new();
+            for (k, v) in obj {
+                map.push((Value::Text(k), json_to_cbor_with(v, integer_strings)));
+            }
+            Value::Map(map)
+        }
+    }
+}
+
+/// The entries of a `__cbor_map_entries__` or `__cbor_ordered_map__` marker; both list
+/// `[key, value]` pairs and encode them in the order given
+fn pairs_marker(obj: &serde_json::Map<String, JsonValue>) -> Option<Vec<(&JsonValue, &JsonValue)>> {
+    obj.get("__cbor_map_entries__")
+        .or_else(|| obj.get("__cbor_ordered_map__"))
+        .and_then(map_entries)
+}
+
+/// Convert a `__cbor_int_map__` key: integer strings become integer keys, others stay text
+///
+/// The marker is shorthand for integer-keyed maps; an object holding both it and
+/// `__cbor_map_entries__` is encoded from the entries.
+fn int_map_key(key: &str) -> Value {
+    match integer_from_string(key) {
+        Some(integer) => Value::Integer(integer),
+        None => Value::Text(key.to_string()),
+    }
+}
+
+/// Read the `[[key, value], ...]` list of a `__cbor_map_entries__` marker
+fn map_entries(entries: &JsonValue) -> Option<Vec<(&JsonValue, &JsonValue)>> {
+    entries
+        .as_array()?
+        .iter()
+        .map(|pair| match pair.as_array().map(Vec::as_slice) {
+            Some([k, v]) => Some((k, v)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Convert a `__cbor_map_entries__` key; strings stay text, as object keys do
+fn entry_key_to_cbor(key: &JsonValue) -> Value {
+    match key {
+        JsonValue::String(s) => Value::Text(s.clone()),
+        other => json_to_cbor(other.clone()),
+    }
+}
+
+/// Sort map entries by the order of their encoded keys that `mode` selects
+fn canonicalize(value: &mut Value, mode: CanonicalMode) {
+    match value {
+        Value::Array(arr) => arr.iter_mut().for_each(|item| canonicalize(item, mode)),
+        Value::Map(map) => {
+            for (k, v) in map.iter_mut() {
+                canonicalize(k, mode);
+                canonicalize(v, mode);
+            }
+            map.sort_by_cached_key(|(k, _)| {
+                let mut key_bytes = Vec::new();
+                // Writing into a Vec cannot fail
+                let _ = ciborium::into_writer(k, &mut key_bytes);
+                mode.sort_key(key_bytes)
+            });
+        }
+        Value::Tag(_, inner) => canonicalize(inner, mode),
+        _ => {}
+    }
+}
+
+/// SHA-256 hex digest of a value's canonical encoding, stable across map key order
+fn canonical_hash(value: &Value) -> String {
+    let mut canonical = value.clone();
+    canonicalize(&mut canonical, CanonicalMode::Bytewise);
+
+    let mut bytes = Vec::new();
+    // Writing into a Vec cannot fail
+    let _ = ciborium::into_writer(&canonical, &mut bytes);
+    hex::encode(Sha256::digest(&bytes))
+}
+
+/// Write a CBOR item header with the shortest argument encoding
+fn write_header(out: &mut Vec<u8>, major: u8, arg: u64) {
+    let major = major << 5;
+    if arg < 24 {
+        out.push(major | arg as u8);
+    } else if let Ok(a) = u8::try_from(arg) {
+        out.extend_from_slice(&[major | 24, a]);
+    } else if let Ok(a) = u16::try_from(arg) {
+        out.push(major | 25);
+        out.extend_from_slice(&a.to_be_bytes());
+    } else if let Ok(a) = u32::try_from(arg) {
+        out.push(major | 26);
+        out.extend_from_slice(&a.to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&arg.to_be_bytes());
+    }
+}
+
+/// The half-precision float with exactly the value of `f`, if there is one
+///
+/// Every NaN maps to a half-precision NaN, since the payload is not part of the value.
+fn exact_half(f: f64) -> Option<f16> {
+    let half = f16::from_f64(f);
+    (f.is_nan() || half.to_f64().to_bits() == f.to_bits()).then_some(half)
+}
+
+/// Write an integer with an argument of exactly `width` bytes (1, 2, 4 or 8), minimal or not
+fn write_int_width(out: &mut Vec<u8>, n: i128, width: u64) -> Result<(), String> {
+    let (major, arg) = if n < 0 { (1, -1 - n) } else { (0, n) };
+    let info = match width {
+        1 => 24,
+        2 => 25,
+        4 => 26,
+        8 => 27,
+        _ => {
+            return Err(format!(
+                "invalid integer width {}, expected 1, 2, 4 or 8",
+                width
+            ))
+        }
+    };
+    // Integer values stay within -2^64..2^64-1, so the argument always fits in a u64
+    let arg = u64::try_from(arg).map_err(|_| format!("integer {} is out of range", n))?;
+    let bytes = arg.to_be_bytes();
+    let (high, low) = bytes.split_at(8 - width as usize);
+    if high.iter().any(|&b| b != 0) {
+        return Err(format!("integer {} does not fit in {} bytes", n, width));
+    }
+    out.push(major << 5 | info);
+    out.extend_from_slice(low);
+    Ok(())
+}
+
+/// Width in bits of the narrowest float encoding that holds `f` exactly
+fn shortest_float_width(f: f64) -> u8 {
+    if exact_half(f).is_some() {
+        16
+    } else if f64::from(f as f32).to_bits() == f.to_bits() {
+        32
+    } else {
+        64
+    }
+}
+
+/// Write a float in the narrowest width that round-trips exactly (RFC 8949 §4.2.2)
+fn write_shortest_float(out: &mut Vec<u8>, f: f64) {
+    let single = f as f32;
+    if f.is_nan() {
+        // Deterministic encoding uses the canonical half-precision quiet NaN
+        out.extend_from_slice(&[0xf9, 0x7e, 0x00]);
+    } else if let Some(half) = exact_half(f) {
+        out.push(0xf9);
+        out.extend_from_slice(&half.to_be_bytes());
+    } else if (single as f64).to_bits() == f.to_bits() {
+        out.push(0xfa);
+        out.extend_from_slice(&single.to_be_bytes());
+    } else {
+        out.push(0xfb);
+        out.extend_from_slice(&f.to_be_bytes());
+    }
+}
+
+/// Write a float at an explicit width of 16, 32 or 64 bits, returning false for any other width
+///
+/// Narrowing rounds to the nearest representable value, as the caller asked for that width.
+fn write_float_width(out: &mut Vec<u8>, f: f64, width: u64) -> bool {
+    match width {
+        16 => {
+            out.push(0xf9);
+            out.extend_from_slice(&f16::from_f64(f).to_be_bytes());
+        }
+        32 => {
+            out.push(0xfa);
+            out.extend_from_slice(&(f as f32).to_be_bytes());
+        }
+        64 => {
+            out.push(0xfb);
+            out.extend_from_slice(&f.to_be_bytes());
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// The chunks of an `__cbor_indefinite_bytes__` marker, each given as a hex string
+fn bytes_chunks(marker: &JsonValue) -> Option<Vec<Vec<u8>>> {
+    marker
+        .as_array()?
+        .iter()
+        .map(|chunk| hex::decode(chunk.as_str()?).ok())
+        .collect()
+}
+
+/// The chunks of an `__cbor_indefinite_text__` marker
+fn text_chunks(marker: &JsonValue) -> Option<Vec<&str>> {
+    marker.as_array()?.iter().map(JsonValue::as_str).collect()
+}
+
+/// True if an object is an ordinary map rather than one of the `__cbor_*__` markers
+fn is_plain_map(obj: &serde_json::Map<String, JsonValue>) -> bool {
+    !obj.keys()
+        .any(|k| k.starts_with("__cbor_") && k.ends_with("__"))
+}
+
+/// Encode JSON straight to CBOR bytes
+///
+/// Containers are written by hand so that markers ciborium's `Value` cannot express
+/// (indefinite-length arrays and maps, undefined, other simple values) can appear at any depth. Everything else is
+/// converted with `json_to_cbor` and serialized by ciborium.
+fn write_json_cbor(
+    value: JsonValue,
+    options: &EncodeOptions,
+    out: &mut Vec<u8>,
+) -> Result<(), ciborium::ser::Error<std::io::Error>> {
+    match value {
+        JsonValue::Number(n)
+            if options.float_shrink && n.as_i64().is_none() && n.as_u64().is_none() =>
+        {
+            write_shortest_float(out, n.as_f64().unwrap_or(f64::NAN));
+            Ok(())
+        }
+        JsonValue::Array(arr) => {
+            write_header(out, 4, arr.len() as u64);
+            for item in arr {
+                write_json_cbor(item, options, out)?;
+            }
+            Ok(())
+        }
+        JsonValue::Object(mut obj) => {
+            if let (Some(int), Some(width)) =
+                (obj.get("__cbor_int__"), obj.get("__cbor_int_width__"))
+            {
+                return match (json_to_cbor(int.clone()), width.as_u64()) {
+                    (Value::Integer(integer), Some(width)) => {
+                        let mut written = Vec::new();
+                        write_int_width(&mut written, i128::from(integer), width)
+                            .map_err(ciborium::ser::Error::Value)?;
+                        if options.canonical
+                            && read_raw_header(&written, 0)
+                                .is_ok_and(|(header, _)| is_non_minimal(&header))
+                        {
+                            return Err(not_canonical(format!(
+                                "__cbor_int_width__ {} is not the shortest form of {}",
+                                width,
+                                i128::from(integer)
+                            )));
+                        }
+                        out.extend_from_slice(&written);
+                        Ok(())
+                    }
+                    _ => Err(ciborium::ser::Error::Value(
+                        "__cbor_int__ needs an integer and a numeric __cbor_int_width__"
+                            .to_string(),
+                    )),
+                };
+            }
+
+            if obj.contains_key("__cbor_undefined__") {
+                out.push(0xf7);
+                return Ok(());
+            }
+
+            if let Some(simple) = obj.get("__cbor_simple__").and_then(|v| v.as_u64()) {
+                // 24-31 have no valid encoding; larger numbers are not simple values
+                return match u8::try_from(simple) {
+                    Ok(simple @ (0..=23 | 32..=255)) => {
+                        write_header(out, 7, simple as u64);
+                        Ok(())
+                    }
+                    _ => Err(ciborium::ser::Error::Value(format!(
+                        "invalid simple value {}",
+                        simple
+                    ))),
+                };
+            }
+
+            for (marker, _) in TEXT_TAG_MARKERS {
+                if obj.get(marker).is_some_and(|v| !v.is_string()) {
+                    return Err(ciborium::ser::Error::Value(format!(
+                        "{} needs a text string",
+                        marker
+                    )));
+                }
+            }
+
+            if options.canonical {
+                let indefinite = [
+                    "__cbor_indefinite_bytes__",
+                    "__cbor_indefinite_text__",
+                    "__cbor_indefinite_array__",
+                    "__cbor_indefinite_map__",
+                ];
+                if let Some(marker) = indefinite.iter().find(|&&m| obj.contains_key(m)) {
+                    return Err(not_canonical(format!("{} has indefinite length", marker)));
+                }
+            }
+
+            if let Some(marker) = obj.get("__cbor_indefinite_bytes__") {
+                let chunks = bytes_chunks(marker).ok_or_else(|| {
+                    ciborium::ser::Error::Value(
+                        "__cbor_indefinite_bytes__ needs an array of hex strings".to_string(),
+                    )
+                })?;
+                out.push(0x5f);
+                for chunk in chunks {
+                    write_header(out, 2, chunk.len() as u64);
+                    out.extend_from_slice(&chunk);
+                }
+                out.push(0xff);
+                return Ok(());
+            }
+
+            if let Some(marker) = obj.get("__cbor_indefinite_text__") {
+                let chunks = text_chunks(marker).ok_or_else(|| {
+                    ciborium::ser::Error::Value(
+                        "__cbor_indefinite_text__ needs an array of strings".to_string(),
+                    )
+                })?;
+                out.push(0x7f);
+                for chunk in chunks {
+                    write_header(out, 3, chunk.len() as u64);
+                    out.extend_from_slice(chunk.as_bytes());
+                }
+                out.push(0xff);
+                return Ok(());
+            }
+
+            if let Some(JsonValue::Array(items)) = obj.remove("__cbor_indefinite_array__") {
+                out.push(0x9f);
+                for item in items {
+                    write_json_cbor(item, options, out)?;
+                }
+                out.push(0xff);
+                return Ok(());
+            }
+
+            if let Some(entries) = pairs_marker(&obj) {
+                let mut encoded = Vec::with_capacity(entries.len());
+                for (k, v) in entries {
+                    let mut key = Vec::new();
+                    match k {
+                        JsonValue::String(_) => {
+                            ciborium::into_writer(&entry_key_to_cbor(k), &mut key)?
+                        }
+                        _ => write_json_cbor(k.clone(), options, &mut key)?,
+                    }
+                    encoded.push((key, v.clone()));
+                }
+                return write_map(out, encoded, options);
+            }
+
+            if let Some(JsonValue::Object(entries)) = obj.remove("__cbor_int_map__") {
+                let mut encoded = Vec::with_capacity(entries.len());
+                for (k, v) in entries {
+                    let mut key = Vec::new();
+                    ciborium::into_writer(&int_map_key(&k), &mut key)?;
+                    encoded.push((key, v));
+                }
+                return write_map(out, encoded, options);
+            }
+
+            if let Some(JsonValue::Object(entries)) = obj.remove("__cbor_indefinite_map__") {
+                out.push(0xbf);
+                for (k, v) in entries {
+                    ciborium::into_writer(&Value::Text(k), &mut *out)?;
+                    write_json_cbor(v, options, out)?;
+                }
+                out.push(0xff);
+                return Ok(());
+            }
+
+            if let Some(f) = obj.get("__cbor_float__").and_then(float_marker_value) {
+                // Spelled out as a double unless the caller asked for the narrowest floats
+                if options.float_shrink || options.force_half || options.canonical {
+                    write_shortest_float(out, f);
+                } else {
+                    write_float_width(out, f, 64);
+                }
+                return Ok(());
+            }
+
+            if let Some(encoded) = obj.get("__cbor_nan_bits__").and_then(nan_from_bits) {
+                // Written verbatim: any trip through f64 would quiet or truncate the payload
+                return write_nan_bits(out, &encoded, options);
+            }
+
+            if let (Some(width), Some(inner)) = (obj.get("__cbor_float_width__"), obj.get("value"))
+            {
+                if let Some(encoded) = inner.get("__cbor_nan_bits__").and_then(nan_from_bits) {
+                    return write_nan_bits(out, &encoded, options);
+                }
+                if let Value::Float(f) = json_to_cbor(inner.clone()) {
+                    let width = width.as_u64().unwrap_or(0);
+                    if options.canonical
+                        && !f.is_nan()
+                        && width != u64::from(shortest_float_width(f))
+                    {
+                        return Err(not_canonical(format!(
+                            "__cbor_float_width__ {} is not the shortest form of {}",
+                            width, f
+                        )));
+                    }
+                    if write_float_width(out, f, width) {
+                        return Ok(());
+                    }
+                }
+            }
+
+            if let Some(inner) = obj.remove("__cbor_encoded_cbor__") {
+                // Tag 24 around a byte string holding the separately encoded item
+                let mut embedded = Vec::new();
+                write_json_cbor(inner, options, &mut embedded)?;
+                write_header(out, 6, 24);
+                write_header(out, 2, embedded.len() as u64);
+                out.extend_from_slice(&embedded);
+                return Ok(());
+            }
+
+            if let Some(inner) = obj.remove("__cbor_bstr_cbor__") {
+                // A plain byte string holding the separately encoded item, as COSE uses
+                let mut embedded = Vec::new();
+                write_json_cbor(inner, options, &mut embedded)?;
+                write_header(out, 2, embedded.len() as u64);
+                out.extend_from_slice(&embedded);
+                return Ok(());
+            }
+
+            if let (Some(tag), Some(inner)) = (obj.get("__cbor_tag__"), obj.get("__cbor_value__")) {
+                if let Some(tag_num) = tag.as_u64() {
+                    write_header(out, 6, tag_num);
+                    return write_json_cbor(inner.clone(), options, out);
+                }
+            }
+
+            if is_plain_map(&obj) {
+                let mut encoded = Vec::with_capacity(obj.len());
+                for (k, v) in obj {
+                    let mut key = Vec::new();
+                    ciborium::into_writer(&Value::Text(k), &mut key)?;
+                    encoded.push((key, v));
+                }
+                return write_map(out, encoded, options);
+            }
+
+            let mut value = json_to_cbor_with(JsonValue::Object(obj), options.integer_strings);
+            if options.canonical {
+                canonicalize(&mut value, options.canonical_mode);
+            }
+            ciborium::into_writer(&value, &mut *out)
+        }
+        other => ciborium::into_writer(
+            &json_to_cbor_with(other, options.integer_strings),
+            &mut *out,
+        ),
+    }
+}
+
+/// Write a map from its encoded keys and their values, in canonical key order when asked
+fn write_map(
+    out: &mut Vec<u8>,
+    entries: Vec<(Vec<u8>, JsonValue)>,
+    options: &EncodeOptions,
+) -> Result<(), ciborium::ser::Error<std::io::Error>> {
+    let mut encoded = Vec::with_capacity(entries.len());
+    for (key, value) in entries {
+        let mut value_bytes = Vec::new();
+        write_json_cbor(value, options, &mut value_bytes)?;
+        encoded.push((key, value_bytes));
+    }
+    if options.canonical {
+        encoded.sort_by_cached_key(|(key, _)| options.canonical_mode.sort_key(key.clone()));
+    }
+    write_header(out, 5, encoded.len() as u64);
+    for (key, value) in encoded {
+        out.extend_from_slice(&key);
+        out.extend_from_slice(&value);
+    }
+    Ok(())
+}
+
+/// Write a NaN from its `__cbor_nan_bits__` encoding; canonical output only has f97e00
+fn write_nan_bits(
+    out: &mut Vec<u8>,
+    encoded: &[u8],
+    options: &EncodeOptions,
+) -> Result<(), ciborium::ser::Error<std::io::Error>> {
+    if options.canonical && encoded != [0xf9, 0x7e, 0x00] {
+        return Err(not_canonical(format!(
+            "NaN {} is not the canonical f97e00",
+            hex::encode(encoded)
+        )));
+    }
+    out.extend_from_slice(encoded);
+    Ok(())
+}
+
+/// The encode error for a marker that asks for something deterministic encoding forbids
+fn not_canonical(reason: String) -> ciborium::ser::Error<std::io::Error> {
+    ciborium::ser::Error::Value(format!("{}, which canonical output cannot carry", reason))
+}
+
+/// A CBOR item header as it appears on the wire
+struct RawHeader {
+    /// Offset of the initial byte
+    offset: usize,
+    /// Major type (0-7)
+    major: u8,
+    /// Additional information (low five bits of the initial byte)
+    info: u8,
+    /// Argument value; None for indefinite lengths and the break code
+    arg: Option<u64>,
+}
+
+/// Read the header starting at `pos`, returning it and the offset just past it
+fn read_raw_header(bytes: &[u8], pos: usize) -> Result<(RawHeader, usize), String> {
+    let initial = *bytes
+        .get(pos)
+        .ok_or_else(|| format!("unexpected end of input at offset {}", pos))?;
+    let major = initial >> 5;
+    let info = initial & 0x1f;
+
+    let width = match info {
+        0..=23 => 0,
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        28..=30 => {
+            return Err(format!(
+                "{} {} at offset {}",
+                RESERVED_INFO_ERROR, info, pos
+            ))
+        }
+        _ => {
+            return Ok((
+                RawHeader {
+                    offset: pos,
+                    major,
+                    info,
+                    arg: None,
+                },
+                pos + 1,
+            ))
+        }
+    };
+
+    let arg = if width == 0 {
+        info as u64
+    } else {
+        let end = pos + 1 + width;
+        let arg_bytes = bytes
+            .get(pos + 1..end)
+            .ok_or_else(|| format!("unexpected end of input at offset {}", bytes.len()))?;
+        arg_bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64)
+    };
+
+    Ok((
+        RawHeader {
+            offset: pos,
+            major,
+            info,
+            arg: Some(arg),
+        },
+        pos + 1 + width,
+    ))
+}
+
+/// Walk the headers of the first CBOR item in wire order, skipping string payloads
+///
+/// The visitor also receives the nesting depth of each header (0 for the root item).
+/// Returns the number of bytes the item occupies. The walk keeps an explicit stack
+/// of open containers rather than recursing, so nesting depth costs no call stack.
+fn scan_headers(bytes: &[u8], visit: &mut dyn FnMut(&RawHeader, usize)) -> Result<usize, String> {
+    // Items still expected by each open container; None for indefinite length
+    let mut pending: Vec<Option<u64>> = vec![Some(1)];
+    let mut pos = 0;
+
+    loop {
+        while let Some(Some(0)) = pending.last() {
+            pending.pop();
+        }
+        if pending.is_empty() {
+            return Ok(pos);
+        }
+
+        let header_pos = pos;
+        let (header, next) = read_raw_header(bytes, pos)?;
+        pos = next;
+        visit(&header, pending.len() - 1);
+
+        if header.major == 7 && header.info == 31 {
+            // Break closes the innermost indefinite container
+            match pending.pop() {
+                Some(None) => continue,
+                _ => return Err(format!("unexpected break at offset {}", header_pos)),
+            }
+        }
+
+        if let Some(Some(remaining)) = pending.last_mut() {
+            *remaining -= 1;
+        }
+
+        match (header.major, header.arg) {
+            (2 | 3, Some(len)) => {
+                pos = usize::try_from(len)
+                    .ok()
+                    .and_then(|len| pos.checked_add(len))
+                    .filter(|&end| end <= bytes.len())
+                    .ok_or_else(|| format!("unexpected end of input at offset {}", bytes.len()))?;
+            }
+            (4, Some(len)) => pending.push(Some(len)),
+            (5, Some(len)) => pending.push(Some(len.saturating_mul(2))),
+            (6, _) => pending.push(Some(1)),
+            (2..=5, None) => pending.push(None),
+            (0 | 1, None) => {
+                return Err(format!(
+                    "indefinite length integer at offset {}",
+                    header_pos
+                ))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Wire position of the item at `pos` and everything inside it, as a tree shaped like the item
+///
+/// Every node has `offset` and `len` in bytes; arrays add `items`, maps add `entries` of
+/// `key`/`value` nodes, tags add `tag` and `content`, and indefinite-length strings add
+/// `chunks`. Returns the tree and the offset just past the item. Recursion is bounded by
+/// the depth check that runs before this.
+fn item_offsets(bytes: &[u8], pos: usize) -> Result<(JsonValue, usize), String> {
+    let (header, mut next) = read_raw_header(bytes, pos)?;
+    let mut node = json!({ "offset": pos });
+
+    // Children of a container: `count` of them, or up to a break when indefinite
+    let children = |count: Option<u64>, next: &mut usize| -> Result<Vec<JsonValue>, String> {
+        let mut nodes = Vec::new();
+        let mut seen = 0;
+        while count.map_or(bytes.get(*next) != Some(&0xff), |count| seen < count) {
+            let (child, end) = item_offsets(bytes, *next)?;
+            nodes.push(child);
+            *next = end;
+            seen += 1;
+        }
+        if count.is_none() {
+            // Step over the break
+            *next += 1;
+        }
+        Ok(nodes)
+    };
+
+    match (header.major, header.arg) {
+        (2 | 3, Some(len)) => {
+            next = usize::try_from(len)
+                .ok()
+                .and_then(|len| next.checked_add(len))
+                .filter(|&end| end <= bytes.len())
+                .ok_or_else(|| format!("unexpected end of input at offset {}", bytes.len()))?;
+        }
+        (2 | 3, None) => node["chunks"] = json!(children(None, &mut next)?),
+        (4, count) => node["items"] = json!(children(count, &mut next)?),
+        (5, count) => {
+            let nodes = children(count.map(|n| n.saturating_mul(2)), &mut next)?;
+            let entries: Vec<JsonValue> = nodes
+                .chunks(2)
+                .map(|pair| json!({ "key": pair[0], "value": pair.get(1) }))
+                .collect();
+            node["entries"] = json!(entries);
+        }
+        (6, Some(tag)) => {
+            let (content, end) = item_offsets(bytes, next)?;
+            node["tag"] = json!(tag);
+            node["content"] = content;
+            next = end;
+        }
+        (7, None) => return Err(format!("unexpected break at offset {}", pos)),
+        (0 | 1, None) => return Err(format!("indefinite length integer at offset {}", pos)),
+        _ => {}
+    }
+
+    node["len"] = json!(next - pos);
+    Ok((node, next))
+}
+
+/// The width in bits of a float header (half, single or double precision)
+fn float_width(header: &RawHeader) -> Option<u8> {
+    match (header.major, header.info) {
+        (7, 25) => Some(16),
+        (7, 26) => Some(32),
+        (7, 27) => Some(64),
+        _ => None,
+    }
+}
+
+/// Encode the `__cbor_nan_bits__` hex of a NaN as the float item it came from
+///
+/// The hex length picks the width (4, 8 or 16 digits); anything that is not a NaN at
+/// that width is ignored so the caller falls back to the canonical NaN.
+fn nan_from_bits(hex: &JsonValue) -> Option<Vec<u8>> {
+    let bits = hex::decode(hex.as_str()?).ok()?;
+    let is_nan = match bits.len() {
+        2 => f16::from_bits(u16::from_be_bytes([bits[0], bits[1]])).is_nan(),
+        4 => f32::from_bits(u32::from_be_bytes(bits[..].try_into().ok()?)).is_nan(),
+        8 => f64::from_bits(u64::from_be_bytes(bits[..].try_into().ok()?)).is_nan(),
+        _ => false,
+    };
+    if !is_nan {
+        return None;
+    }
+    let header = match bits.len() {
+        2 => 0xf9,
+        4 => 0xfa,
+        _ => 0xfb,
+    };
+    let mut encoded = vec![header];
+    encoded.extend_from_slice(&bits);
+    Some(encoded)
+}
+
+/// The simple value a header carries if ciborium will read it as Value::Null
+///
+/// That is null and undefined themselves plus every simple value ciborium has no
+/// variant for, which mask_simple_values rewrites to null before decoding.
+fn null_like_simple(header: &RawHeader) -> Option<u8> {
+    if header.major != 7 || header.info > 24 {
+        return None;
+    }
+    match header.arg {
+        Some(20 | 21) => None,
+        // The two-byte form of 0..=31 is not well-formed, so it is left for ciborium to reject
+        Some(0..=31) if header.info == 24 => None,
+        Some(simple) => u8::try_from(simple).ok(),
+        None => None,
+    }
+}
+
+/// Whether `header` opens a negative bignum that ciborium cannot read
+///
+/// ciborium turns tag 3 over a definite byte string of at most 16 bytes into an i128,
+/// which overflows once the magnitude fills all 16 bytes with the top bit set.
+fn is_overflowing_bignum(bytes: &[u8], header: &RawHeader) -> bool {
+    if header.major != 6 || header.arg != Some(3) {
+        return false;
+    }
+    let content =
+        read_raw_header(bytes, header.offset).and_then(|(_, next)| read_raw_header(bytes, next));
+    match content {
+        Ok((content, start)) if content.major == 2 && content.arg == Some(16) => {
+            bytes.get(start).is_some_and(|&first| first >= 0x80)
+        }
+        _ => false,
+    }
+}
+
+/// Rewrite items that ciborium rejects into ones it reads, so the input still decodes
+///
+/// Simple values it has no variant for become null and negative bignums it would overflow
+/// on become tag 23. Each rewrite keeps the byte length (`0xf6` or `0xf8 0x16`, and the
+/// tag number's last byte), so offsets are unchanged; DecodeState recovers the original
+/// simple values and read_item the original tags from the unmodified bytes. Every item
+/// of a sequence is covered; scanning stops quietly at the first malformed item.
+fn mask_simple_values(bytes: &[u8]) -> Cow<'_, [u8]> {
+    let mut masked = Cow::Borrowed(bytes);
+    let mut start = 0;
+    while start < bytes.len() {
+        let mut positions = Vec::new();
+        let scanned = scan_headers(&bytes[start..], &mut |header, _| {
+            if matches!(null_like_simple(header), Some(s) if s != SIMPLE_NULL && s != SIMPLE_UNDEFINED)
+                || is_overflowing_bignum(&bytes[start..], header)
+            {
+                positions.push((start + header.offset, header.major, header.info));
+            }
+        });
+
+        for (pos, major, info) in positions {
+            let out = masked.to_mut();
+            match (major, info) {
+                (7, 24) => out[pos + 1] = SIMPLE_NULL,
+                (7, _) => out[pos] = 0xf6,
+                (_, 0..=23) => out[pos] = 0xc0 | MASKED_BIGNUM_TAG,
+                // The argument is big-endian, so its last byte holds the 3
+                (_, _) => out[pos + (1 << (info - 24))] = MASKED_BIGNUM_TAG,
+            }
+        }
+
+        match scanned {
+            Ok(len) => start += len,
+            Err(_) => break,
+        }
+    }
+    masked
+}
+
+/// Put back the simple values mask_simple_values turned into null, in wire order
+///
+/// `written` is ciborium's encoding of a Value read from masked bytes: it writes each
+/// Value::Null as `0xf6`, in the same order as the nulls of the original, and `next_origin`
+/// (a DecodeState scanned over the original, or parse_diag's record) says what each one was.
+fn unmask_simple_values(written: &[u8], mut next_origin: impl FnMut() -> u8) -> Vec<u8> {
+    let mut nulls = Vec::new();
+    let _ = scan_headers(written, &mut |header, _| {
+        if null_like_simple(header).is_some() {
+            nulls.push(header.offset);
+        }
+    });
+
+    let mut out = Vec::with_capacity(written.len());
+    let mut copied = 0;
+    for offset in nulls {
+        out.extend_from_slice(&written[copied..offset]);
+        write_header(&mut out, 7, u64::from(next_origin()));
+        copied = offset + 1;
+    }
+    out.extend_from_slice(&written[copied..]);
+    out
+}
+
+/// Reject the first item of `bytes` if arrays, maps and tags nest deeper than `limit`
+///
+/// The scan is iterative, so this runs before any recursive decoding touches the input.
+/// Malformed input passes through so ciborium can report the actual error.
+fn check_depth(bytes: &[u8], limit: usize) -> Result<(), JsonValue> {
+    let mut deepest = 0;
+    let _ = scan_headers(bytes, &mut |header, depth| {
+        if matches!(header.major, 4..=6) {
+            deepest = deepest.max(depth + 1);
+        }
+    });
+
+    if deepest > limit {
+        return Err(error_response(
+            ErrorCode::DepthExceeded,
+            "max depth exceeded",
+        ));
+    }
+    Ok(())
+}
+
+/// Reject the first item of `bytes` if any header uses reserved additional information
+///
+/// Other malformations pass through so ciborium can report them as usual.
+fn check_reserved(bytes: &[u8]) -> Result<(), JsonValue> {
+    match scan_headers(bytes, &mut |_, _| {}) {
+        Err(e) if e.starts_with(RESERVED_INFO_ERROR) => {
+            Err(error_response(ErrorCode::ReservedEncoding, e))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Reject the first item of `bytes` if any string, array or map in it has indefinite length
+///
+/// ciborium reads these like their definite-length forms, so only the raw headers tell.
+fn check_definite(bytes: &[u8]) -> Result<(), JsonValue> {
+    let mut first = None;
+    let _ = scan_headers(bytes, &mut |header, _| {
+        if first.is_none() && matches!(header.major, 2..=5) && header.info == 31 {
+            first = Some(header.offset);
+        }
+    });
+    match first {
+        Some(offset) => Err(error_response(
+            ErrorCode::IndefiniteForbidden,
+            format!(
+                "indefinite-length item (0x{:02x}) at offset {}",
+                bytes[offset], offset
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Whether a header's argument would fit a shorter encoding, e.g. `0x18 0x17` for 23
+///
+/// Major type 7 is exempt: its 2/4/8-byte forms are floats of different precision.
+fn is_non_minimal(header: &RawHeader) -> bool {
+    let Some(arg) = header.arg else {
+        return false;
+    };
+    header.major != 7
+        && match header.info {
+            24 => arg < 24,
+            25 => arg <= 0xff,
+            26 => arg <= 0xffff,
+            27 => arg <= 0xffff_ffff,
+            _ => false,
+        }
+}
+
+/// Whether any header in the first item of `bytes` is non-minimally encoded
+fn has_non_minimal_header(bytes: &[u8]) -> bool {
+    let mut found = false;
+    let _ = scan_headers(bytes, &mut |header, _| found |= is_non_minimal(header));
+    found
+}
+
+/// Whether the first item of `bytes` is in RFC 8949 deterministic form (§4.2.1)
+///
+/// That is: definite lengths only, every argument in its shortest form, every float in
+/// the narrowest width that holds it exactly (NaN as half precision), and map keys
+/// strictly increasing in the bytewise order of their encodings.
+fn is_canonical(bytes: &[u8]) -> bool {
+    let mut canonical = true;
+    let scanned = scan_headers(bytes, &mut |header, _| {
+        let indefinite = matches!(header.major, 2..=5) && header.info == 31;
+        let wide_float = match (float_width(header), header.arg) {
+            (Some(32), Some(bits)) => {
+                let f = f64::from(f32::from_bits(bits as u32));
+                f.is_nan() || shortest_float_width(f) < 32
+            }
+            (Some(64), Some(bits)) => {
+                let f = f64::from_bits(bits);
+                f.is_nan() || shortest_float_width(f) < 64
+            }
+            _ => false,
+        };
+        canonical &= !(indefinite || wide_float || is_non_minimal(header));
+    });
+
+    canonical
+        && scanned.is_ok()
+        && item_offsets(bytes, 0).is_ok_and(|(tree, _)| map_keys_sorted(&tree, bytes))
+}
+
+/// Whether every map in an `item_offsets` tree has its keys in strictly increasing
+/// bytewise order of their encodings
+fn map_keys_sorted(node: &JsonValue, bytes: &[u8]) -> bool {
+    let span = |node: &JsonValue| {
+        let offset = node["offset"].as_u64().unwrap_or_default() as usize;
+        let len = node["len"].as_u64().unwrap_or_default() as usize;
+        &bytes[offset..offset + len]
+    };
+    if let Some(entries) = node["entries"].as_array() {
+        let keys: Vec<&[u8]> = entries.iter().map(|entry| span(&entry["key"])).collect();
+        if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return false;
+        }
+        return entries.iter().all(|entry| {
+            map_keys_sorted(&entry["key"], bytes) && map_keys_sorted(&entry["value"], bytes)
+        });
+    }
+    node["items"]
+        .as_array()
+        .is_none_or(|items| items.iter().all(|item| map_keys_sorted(item, bytes)))
+        && node
+            .get("content")
+            .is_none_or(|content| map_keys_sorted(content, bytes))
+}
+
+/// Nesting depth of arrays, maps and tagged markers in a JSON value
+///
+/// Recursion is bounded because serde_json refuses input nested deeper than 128 levels.
+fn json_depth(value: &JsonValue) -> usize {
+    match value {
+        JsonValue::Array(arr) => 1 + arr.iter().map(json_depth).max().unwrap_or(0),
+        JsonValue::Object(obj) => 1 + obj.values().map(json_depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Decode a hex string into bytes, returning an error response on failure
+///
+/// ASCII whitespace is ignored so split hex dumps work as they are, a leading `0x`/`0X` is
+/// dropped and either case is accepted, so `0xDEAD`, `DE AD` and `dead` are the same input.
+pub fn decode_hex(hex_string: &str) -> Result<Vec<u8>, JsonValue> {
+    let compact: String = hex_string
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect();
+    let digits = compact
+        .strip_prefix("0x")
+        .or_else(|| compact.strip_prefix("0X"))
+        .unwrap_or(&compact)
+        .to_ascii_lowercase();
+    if digits.len() & 1 == 1 {
+        return Err(error_response(
+            ErrorCode::InvalidHex,
+            format!(
+                "Invalid hex: odd number of digits ({}), each byte needs two",
+                digits.len()
+            ),
+        ));
+    }
+    hex::decode(&digits)
+        .map_err(|e| error_response(ErrorCode::InvalidHex, format!("Invalid hex: {}", e)))
+}
+
+/// Reader that counts the bytes ciborium has consumed, which locates a failed read
+struct CountingReader<R> {
+    inner: R,
+    consumed: usize,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.consumed += n;
+        Ok(n)
+    }
+}
+
+/// Read the first item of `bytes` from `masked`, its mask_simple_values rewrite
+///
+/// Returns the item with the negative bignums the mask turned into tag 23 restored,
+/// and the number of bytes it occupies.
+fn read_item(bytes: &[u8], masked: &[u8]) -> Result<(Value, usize), JsonValue> {
+    let mut reader = masked;
+    let mut value = read_cbor(&mut reader)?;
+    let len = masked.len() - reader.len();
+
+    // One flag per tag 23 in wire order, true where the original said tag 3
+    let mut flags = Vec::new();
+    let _ = scan_headers(&bytes[..len], &mut |header, _| {
+        if is_overflowing_bignum(bytes, header) {
+            flags.push(true);
+        } else if header.major == 6 && header.arg == Some(u64::from(MASKED_BIGNUM_TAG)) {
+            flags.push(false);
+        }
+    });
+    if flags.contains(&true) {
+        restore_bignum_tags(&mut value, &mut flags.into_iter());
+    }
+    Ok((value, len))
+}
+
+/// Turn each Value::Tag(23) whose flag is set back into tag 3, visiting in wire order
+fn restore_bignum_tags(value: &mut Value, flags: &mut impl Iterator<Item = bool>) {
+    match value {
+        Value::Tag(tag, inner) => {
+            if *tag == u64::from(MASKED_BIGNUM_TAG) && flags.next() == Some(true) {
+                *tag = 3;
+            }
+            restore_bignum_tags(inner, flags);
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| restore_bignum_tags(item, flags)),
+        Value::Map(entries) => entries.iter_mut().for_each(|(k, v)| {
+            restore_bignum_tags(k, flags);
+            restore_bignum_tags(v, flags);
+        }),
+        _ => {}
+    }
+}
+
+/// Parse the first CBOR item from bytes, returning an error response on failure
+///
+/// The response carries `error_offset` when the failing byte is known: ciborium reports it for
+/// syntax errors, and an I/O error (the input ran out) happens after the bytes consumed so far.
+fn read_cbor<R: Read>(bytes: R) -> Result<Value, JsonValue> {
+    let mut reader = CountingReader {
+        inner: bytes,
+        consumed: 0,
+    };
+    ciborium::from_reader(&mut reader).map_err(|e| {
+        let offset = match &e {
+            ciborium::de::Error::Syntax(offset) => Some(*offset),
+            ciborium::de::Error::Semantic(offset, _) => *offset,
+            ciborium::de::Error::Io(_) => Some(reader.consumed),
+            ciborium::de::Error::RecursionLimitExceeded => None,
+        };
+        let mut response =
+            error_response(ErrorCode::CborDecode, format!("CBOR decode error: {}", e));
+        if let Some(offset) = offset {
+            response["error_offset"] = json!(offset);
+        }
+        response
+    })
+}
+
+/// Decode CBOR hex string
+pub fn decode_cbor(hex_string: &str, options: &DecodeOptions) -> JsonValue {
+    let start = Instant::now();
+    let bytes = match options.encoding.decode(hex_string) {
+        Ok(bytes) => bytes,
+        Err(e) => return e,
+    };
+    let hex_decode_ms = elapsed_ms(start);
+
+    let mut response = decode_cbor_bytes(&bytes, options);
+    if response["success"] == true {
+        response["hex_decode_ms"] = json!(hex_decode_ms);
+        response["duration_ms"] = json!(elapsed_ms(start));
+    }
+    response
+}
+
+/// Milliseconds since `start` on the monotonic clock
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+/// Decode raw CBOR bytes
+pub fn decode_cbor_bytes(bytes: &[u8], options: &DecodeOptions) -> JsonValue {
+    let start = Instant::now();
+
+    if let Err(e) = check_depth(bytes, options.depth_limit) {
+        return e;
+    }
+
+    if options.strict {
+        if let Err(e) = check_reserved(bytes) {
+            return e;
+        }
+    }
+
+    if options.reject_indefinite {
+        if let Err(e) = check_definite(bytes) {
+            return e;
+        }
+    }
+
+    let (value, len) = match read_item(bytes, &mask_simple_values(bytes)) {
+        Ok(item) => item,
+        Err(e) => return e,
+    };
+    let trailing = bytes.len() - len;
+    if trailing > 0 && options.reject_trailing {
+        return json!({
+            "success": false,
+            "trailing_bytes": trailing,
+            "error_code": ErrorCode::TrailingBytes.as_str(),
+            "error": format!("{} trailing bytes after first item", trailing)
+        });
+    }
+    let cbor_decode_ms = elapsed_ms(start);
+
+    let convert_start = Instant::now();
+    let max_depth = value_depth(&value);
+    let hash = canonical_hash(&value);
+    let histogram = options.stats.then(|| {
+        let mut histogram = BTreeMap::new();
+        type_histogram(&value, &mut histogram);
+        histogram
+    });
+    let offsets = if options.offsets {
+        match item_offsets(bytes, 0) {
+            Ok((tree, _)) => Some(tree),
+            Err(e) => {
+                return error_response(ErrorCode::CborDecode, format!("CBOR decode error: {}", e))
+            }
+        }
+    } else {
+        None
+    };
+    let root_is_tag = matches!(value, Value::Tag(..));
+    let mut state = DecodeState::scan(bytes, options.clone());
+    let converted = if options.cose {
+        cose_to_json(value, &mut state)
+    } else {
+        cbor_to_json(value, &mut state)
+    };
+    let json_result = match converted {
+        Ok(j) => j,
+        Err(e) => return e,
+    };
+    let (root_tag, json_result) = split_root_tag(json_result, root_is_tag);
+    let json_result = match &options.path {
+        Some(path) => match resolve_path(json_result, path) {
+            Ok(found) => found,
+            Err(e) => return e,
+        },
+        None => json_result,
+    };
+    if let Some(cap) = options.max_output_bytes {
+        if let Err(e) = check_output_size(&json_result, cap) {
+            return e;
+        }
+    }
+    let convert_ms = elapsed_ms(convert_start);
+    let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let mut response = json!({
+        "success": true,
+        "result": json_result,
+        "duration_ms": duration_ms,
+        "cbor_decode_ms": cbor_decode_ms,
+        "convert_ms": convert_ms,
+        "bytes": bytes.len(),
+        "max_depth": max_depth,
+        "hash": hash,
+        "trailing_bytes": trailing
+    });
+    if let Some(tag) = root_tag {
+        response["tag"] = tag;
+    }
+    if options.check_minimal {
+        response["non_minimal"] = json!(has_non_minimal_header(bytes));
+    }
+    if options.check_canonical {
+        response["is_canonical"] = json!(is_canonical(bytes));
+    }
+    if let Some(histogram) = histogram {
+        response["type_histogram"] = json!(histogram);
+    }
+    if let Some(offsets) = offsets {
+        response["offsets"] = offsets;
+    }
+    if options.warnings {
+        let mut warnings = wire_warnings(bytes);
+        warnings.append(&mut state.warnings);
+        response["warnings"] = json!(warnings);
+    }
+    response
+}
+
+/// The value at a dotted path such as `items.0.name` in a converted result
+///
+/// Each segment is an index into an array or a key of an object; the empty path is the
+/// whole value.
+fn resolve_path(mut value: JsonValue, path: &str) -> Result<JsonValue, JsonValue> {
+    if path.is_empty() {
+        return Ok(value);
+    }
+    for (depth, segment) in path.split('.').enumerate() {
+        let found = match &mut value {
+            JsonValue::Array(items) => segment
+                .parse::<usize>()
+                .ok()
+                .filter(|&index| index < items.len())
+                .map(|index| items.swap_remove(index)),
+            JsonValue::Object(obj) => obj.remove(segment),
+            _ => None,
+        };
+        value = found.ok_or_else(|| {
+            let resolved = path.split('.').take(depth).collect::<Vec<_>>().join(".");
+            error_response(
+                ErrorCode::PathNotFound,
+                format!(
+                    "path {} not found: no '{}' under '{}'",
+                    path, segment, resolved
+                ),
+            )
+        })?;
+    }
+    Ok(value)
+}
+
+/// Fail if `value` serializes to more than `cap` bytes of JSON
+///
+/// The serialization is only counted, never stored, and stops as soon as it passes the cap.
+fn check_output_size(value: &JsonValue, cap: usize) -> Result<(), JsonValue> {
+    struct CappedCount {
+        written: usize,
+        cap: usize,
+    }
+
+    impl Write for CappedCount {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written += buf.len();
+            if self.written > self.cap {
+                return Err(std::io::Error::other("output cap exceeded"));
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut count = CappedCount { written: 0, cap };
+    serde_json::to_writer(&mut count, value).map_err(|_| {
+        error_response(
+            ErrorCode::OutputTooLarge,
+            format!("JSON result exceeds max_output_bytes ({})", cap),
+        )
+    })
+}
+
+/// Encoding choices in the raw bytes that no JSON result can carry back
+fn wire_warnings(bytes: &[u8]) -> Vec<String> {
+    let mut indefinite = 0;
+    let _ = scan_headers(bytes, &mut |header, _| {
+        if matches!(header.major, 2..=5) && header.info == 31 {
+            indefinite += 1;
+        }
+    });
+
+    let mut warnings = Vec::new();
+    if indefinite > 0 {
+        warnings.push(format!(
+            "{} indefinite-length items read as definite-length",
+            indefinite
+        ));
+    }
+    if has_non_minimal_header(bytes) {
+        warnings.push("non-minimal argument encodings not preserved".to_string());
+    }
+    warnings
+}
+
+/// Lift a generic `__cbor_tag__` marker at the root into a separate tag number and content
+///
+/// Tags with a dedicated marker (bignums, date/times) and nested tags are left as they are.
+/// `root_is_tag` says whether the decoded item was a tag, since an untagged map holding just
+/// `__cbor_tag__` and `__cbor_value__` keys converts to the same JSON.
+fn split_root_tag(result: JsonValue, root_is_tag: bool) -> (Option<JsonValue>, JsonValue) {
+    match result {
+        JsonValue::Object(mut obj)
+            if root_is_tag
+                && obj.len() == 2
+                && obj.contains_key("__cbor_tag__")
+                && obj.contains_key("__cbor_value__") =>
+        {
+            let tag = obj.remove("__cbor_tag__");
+            (tag, obj.remove("__cbor_value__").unwrap_or_default())
+        }
+        other => (None, other),
+    }
+}
+
+/// Reader that writes a top-level CBOR array out as JSON, converting one item per chunk
+///
+/// Backs `/decode?stream=1`: only the current item's Value and JSON exist at a time rather
+/// than the whole tree, and tiny_http sends the output with chunked transfer encoding. The
+/// envelope is `{"result": [...], "success": true}` with the outcome written last, so an
+/// item that fails to decode after the 200 status has gone out ends the array and reports
+/// `"success": false` with the error there instead. Problems found before streaming starts
+/// come back as an ordinary error response.
+pub struct ArrayStream {
+    bytes: Vec<u8>,
+    /// `bytes` after mask_simple_values, which is what ciborium reads
+    masked: Vec<u8>,
+    /// Offset of the next item
+    pos: usize,
+    /// Items left in a definite-length array; None for indefinite length
+    remaining: Option<u64>,
+    /// Whether the opening of the envelope has been written
+    opened: bool,
+    /// Number of items written so far
+    written: usize,
+    finished: bool,
+    options: DecodeOptions,
+    /// Output of the last converted item not yet read
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl ArrayStream {
+    /// Check the whole payload up front and position the stream at the first array item
+    pub fn start(bytes: Vec<u8>, options: DecodeOptions) -> Result<Self, JsonValue> {
+        check_depth(&bytes, options.depth_limit)?;
+        if options.strict {
+            check_reserved(&bytes)?;
+        }
+        let decode_error =
+            |e: String| error_response(ErrorCode::CborDecode, format!("CBOR decode error: {}", e));
+        let len = scan_headers(&bytes, &mut |_, _| {}).map_err(decode_error)?;
+        let trailing = bytes.len() - len;
+        if trailing > 0 && options.reject_trailing {
+            return Err(json!({
+                "success": false,
+                "trailing_bytes": trailing,
+                "error_code": ErrorCode::TrailingBytes.as_str(),
+                "error": format!("{} trailing bytes after first item", trailing)
+            }));
+        }
+
+        let (header, pos) = read_raw_header(&bytes, 0).map_err(decode_error)?;
+        if header.major != 4 {
+            return Err(error_response(
+                ErrorCode::NotArray,
+                "stream=1 needs a top-level array",
+            ));
+        }
+
+        Ok(ArrayStream {
+            masked: mask_simple_values(&bytes).into_owned(),
+            bytes,
+            pos,
+            remaining: header.arg,
+            opened: false,
+            written: 0,
+            finished: false,
+            options,
+            chunk: Cursor::new(Vec::new()),
+        })
+    }
+
+    /// Produce the next piece of output: the opening, one item, or the closing outcome
+    fn next_chunk(&mut self) -> Vec<u8> {
+        if !self.opened {
+            self.opened = true;
+            return b"{\"result\":[".to_vec();
+        }
+        let at_end = match self.remaining {
+            Some(n) => n == 0,
+            None => self.masked.get(self.pos) == Some(&0xff),
+        };
+        let mut chunk = Vec::new();
+        if at_end {
+            self.finish(&mut chunk, json!({"success": true}));
+            return chunk;
+        }
+
+        match self.next_item() {
+            Ok(converted) => {
+                if self.written > 0 {
+                    chunk.push(b',');
+                }
+                chunk.extend_from_slice(converted.to_string().as_bytes());
+                self.written += 1;
+            }
+            Err(e) => {
+                let mut outcome = e;
+                outcome["error"] = json!(format!(
+                    "item {}: {}",
+                    self.written,
+                    outcome["error"].as_str().unwrap_or_default()
+                ));
+                outcome["items_decoded"] = json!(self.written);
+                self.finish(&mut chunk, outcome);
+            }
+        }
+        chunk
+    }
+
+    /// Decode and convert the item at `pos`, moving past it
+    fn next_item(&mut self) -> Result<JsonValue, JsonValue> {
+        let (value, len) = read_item(&self.bytes[self.pos..], &self.masked[self.pos..])?;
+        let end = self.pos + len;
+        let mut state = DecodeState::scan(&self.bytes[self.pos..end], self.options.clone());
+        let converted = cbor_to_json(value, &mut state)?;
+
+        self.pos = end;
+        if let Some(n) = self.remaining.as_mut() {
+            *n -= 1;
+        }
+        Ok(converted)
+    }
+
+    /// Close the array and append the remaining envelope fields
+    fn finish(&mut self, chunk: &mut Vec<u8>, outcome: JsonValue) {
+        chunk.push(b']');
+        if let JsonValue::Object(fields) = outcome {
+            for (key, value) in fields {
+                chunk.extend_from_slice(format!(",{}:{}", json!(key), value).as_bytes());
+            }
+        }
+        chunk.push(b'}');
+        self.finished = true;
+    }
+}
+
+impl Read for ArrayStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || self.finished {
+                return Ok(n);
+            }
+            // A panic while converting an item ends this response rather than the worker
+            let next = panic::catch_unwind(AssertUnwindSafe(|| self.next_chunk()));
+            self.chunk = Cursor::new(next.unwrap_or_else(|_| {
+                let mut chunk = Vec::new();
+                let outcome = error_response(ErrorCode::Panic, "internal panic");
+                self.finish(&mut chunk, outcome);
+                chunk
+            }));
+        }
+    }
+}
+
+/// Decode every item of a CBOR sequence (RFC 8742) from a hex string
+pub fn decode_cbor_seq(hex_string: &str, options: &DecodeOptions) -> JsonValue {
+    let start = Instant::now();
+
+    let bytes = match options.encoding.decode(hex_string) {
+        Ok(b) => b,
+        Err(e) => return e,
+    };
+
+    let masked = mask_simple_values(&bytes);
+    let mut items = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let item_bytes = &bytes[pos..];
+        if let Err(e) = check_depth(item_bytes, options.depth_limit) {
+            return e;
+        }
+
+        if options.strict {
+            if let Err(e) = check_reserved(item_bytes) {
+                return e;
+            }
+        }
+
+        let decoded = read_item(item_bytes, &masked[pos..]).and_then(|(value, len)| {
+            pos += len;
+            let mut state = DecodeState::scan(item_bytes, options.clone());
+            cbor_to_json(value, &mut state)
+        });
+
+        match decoded {
+            Ok(item) => items.push(item),
+            Err(mut e) => {
+                // Offsets count from the start of the sequence, not of the failing item
+                if let Some(offset) = e["error_offset"].as_u64() {
+                    e["error_offset"] = json!(offset as usize + pos);
+                }
+                let message = e["error"].as_str().unwrap_or_default();
+                e["error"] = json!(format!("item {}: {}", items.len(), message));
+                e["items_decoded"] = json!(items.len());
+                return e;
+            }
+        }
+    }
+
+    let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    json!({
+        "success": true,
+        "items": items,
+        "duration_ms": duration_ms
+    })
+}
+
+/// Compare two CBOR hex strings for semantic equality
+pub fn compare_cbor(hex_a: &str, hex_b: &str) -> JsonValue {
+    let mut items = Vec::with_capacity(2);
+    for hex_string in [hex_a, hex_b] {
+        let bytes = match decode_hex(hex_string) {
+            Ok(b) => b,
+            Err(e) => return e,
+        };
+        match read_item(&bytes, &mask_simple_values(&bytes)) {
+            Ok((v, _)) => items.push((v, bytes)),
+            Err(e) => return e,
+        }
+    }
+
+    // Collected once the values are in place, since the origins are keyed by address
+    let [a, b] = [&items[0], &items[1]].map(|(value, bytes)| {
+        NullOrigins::collect(
+            value,
+            &mut DecodeState::scan(bytes, DecodeOptions::default()),
+        )
+    });
+    match first_difference(&items[0].0, &items[1].0, "root", [&a, &b]) {
+        None => json!({
+            "success": true,
+            "equal": true
+        }),
+        Some(reason) => json!({
+            "success": true,
+            "equal": false,
+            "reason": reason
+        }),
+    }
+}
+
+/// Decode CBOR hex string into diagnostic notation
+pub fn diagnostic_cbor(hex_string: &str) -> JsonValue {
+    let start = Instant::now();
+
+    let bytes = match decode_hex(hex_string) {
+        Ok(b) => b,
+        Err(e) => return e,
+    };
+
+    let value = match read_item(&bytes, &mask_simple_values(&bytes)) {
+        Ok((v, _)) => v,
+        Err(e) => return e,
+    };
+
+    let diag = cbor_to_diag(
+        value,
+        &mut DecodeState::scan(&bytes, DecodeOptions::default()),
+    );
+    let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    json!({
+        "success": true,
+        "result": diag,
+        "duration_ms": duration_ms
+    })
+}
+
+/// List the top-level keys of a CBOR map in wire order
+///
+/// Text keys are plain strings; any other key is `{"key": <diagnostic notation>, "type": <major>}`
+/// with the type spelled as in type_histogram.
+pub fn keys_cbor(hex_string: &str) -> JsonValue {
+    let bytes = match decode_hex(hex_string) {
+        Ok(b) => b,
+        Err(e) => return e,
+    };
+
+    let value = match read_item(&bytes, &mask_simple_values(&bytes)) {
+        Ok((v, _)) => v,
+        Err(e) => return e,
+    };
+    let Value::Map(map) = &value else {
+        return error_response(
+            ErrorCode::NotMap,
+            format!("top-level item is {}, not a map", major_type_name(&value)),
+        );
+    };
+
+    let nulls = NullOrigins::collect(
+        &value,
+        &mut DecodeState::scan(&bytes, DecodeOptions::default()),
+    );
+    let keys: Vec<JsonValue> = map
+        .iter()
+        .map(|(key, _)| match key {
+            Value::Text(s) => json!(s),
+            other => json!({ "key": diag_value(other, &nulls), "type": major_type_name(other) }),
+        })
+        .collect();
+
+    json!({
+        "success": true,
+        "count": keys.len(),
+        "keys": keys
+    })
+}
+
+/// Check that a CBOR hex string is a single well-formed item
+pub fn validate_cbor(hex_string: &str) -> JsonValue {
+    let bytes = match decode_hex(hex_string) {
+        Ok(b) => b,
+        Err(e) => return e,
+    };
+
+    // Decode from a shrinking slice so the leftover length is the trailing byte count
+    let masked = mask_simple_values(&bytes);
+    let mut reader = &masked[..];
+    if let Err(e) = ciborium::from_reader::<Value, _>(&mut reader) {
+        return json!({
+            "success": true,
+            "valid": false,
+            "error_code": ErrorCode::CborDecode.as_str(),
+            "error": format!("CBOR decode error: {}", e)
+        });
+    }
+
+    let trailing = reader.len();
+    if trailing > 0 {
+        return json!({
+            "success": true,
+            "valid": false,
+            "trailing_bytes": trailing,
+            "error_code": ErrorCode::TrailingBytes.as_str(),
+            "error": format!("{} trailing bytes after first item", trailing)
+        });
+    }
+
+    json!({
+        "success": true,
+        "valid": true,
+        "trailing_bytes": 0
+    })
+}
+
+/// Decode and re-encode CBOR at the Value level, reporting whether the bytes survive unchanged
+pub fn roundtrip_cbor(hex_string: &str) -> JsonValue {
+    let start = Instant::now();
+
+    let bytes = match decode_hex(hex_string) {
+        Ok(b) => b,
+        Err(e) => return e,
+    };
+
+    let value = match read_item(&bytes, &mask_simple_values(&bytes)) {
+        Ok((v, _)) => v,
+        Err(e) => return e,
+    };
+
+    let mut written = Vec::new();
+    if let Err(e) = ciborium::into_writer(&value, &mut written) {
+        return error_response(ErrorCode::CborEncode, format!("CBOR encode error: {}", e));
+    }
+    let mut state = DecodeState::scan(&bytes, DecodeOptions::default());
+    let reencoded = unmask_simple_values(&written, || state.next_null_origin());
+
+    let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    json!({
+        "success": true,
+        "stable": reencoded == bytes,
+        "reencoded_hex": hex::encode(&reencoded),
+        "duration_ms": duration_ms
+    })
+}
+
+/// Time an operation over the "hex" payload repeatedly, for /bench
+///
+/// "op" is decode (what /decode does), encode (/encode of the decoded value) or roundtrip
+/// (both in turn); the request's decode and encode options apply. One untimed run checks
+/// the operation succeeds, then each iteration's result is dropped through `black_box`.
+pub fn bench_cbor(hex_string: &str, json: &JsonValue) -> JsonValue {
+    let op = json.get("op").and_then(|v| v.as_str()).unwrap_or("decode");
+    let iterations = json
+        .get("iterations")
+        .map_or(Some(DEFAULT_BENCH_ITERATIONS), |v| v.as_u64())
+        .filter(|n| (1..=MAX_BENCH_ITERATIONS).contains(n));
+    let Some(iterations) = iterations else {
+        return error_response(
+            ErrorCode::InvalidIterations,
+            format!("iterations must be 1 to {}", MAX_BENCH_ITERATIONS),
+        );
+    };
+    let options = match DecodeOptions::from_request(json)
+        .and_then(|decode| Ok((decode, EncodeOptions::from_request(json)?)))
+    {
+        Ok(options) => options,
+        Err(e) => return e,
+    };
+    let (decode_options, encode_options) = options;
+
+    let bytes = match decode_options.encoding.decode(hex_string) {
+        Ok(b) => b,
+        Err(e) => return e,
+    };
+    // The value /encode would be given for these bytes, root tag included
+    let masked = mask_simple_values(&bytes);
+    let value = match read_item(&bytes, &masked).and_then(|(value, _)| {
+        cbor_to_json(
+            value,
+            &mut DecodeState::scan(&bytes, decode_options.clone()),
+        )
+    }) {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+
+    let run: Box<dyn Fn() -> Result<(), JsonValue>> = match op {
+        "decode" => Box::new(|| {
+            let decoded = decode_cbor_bytes(&bytes, &decode_options);
+            if decoded["success"] == true {
+                Ok(())
+            } else {
+                Err(decoded)
+            }
+        }),
+        "encode" => Box::new(|| encode_cbor_value(value.clone(), &encode_options).map(drop)),
+        "roundtrip" => Box::new(|| {
+            let decoded = read_item(&bytes, &masked).and_then(|(v, _)| {
+                cbor_to_json(v, &mut DecodeState::scan(&bytes, decode_options.clone()))
+            })?;
+            encode_cbor_value(decoded, &encode_options).map(drop)
+        }),
+        other => {
+            return error_response(
+                ErrorCode::UnknownOperation,
+                format!(
+                    "Unknown op: {} (expected decode, encode or roundtrip)",
+                    other
+                ),
+            )
+        }
+    };
+    if let Err(e) = run() {
+        return e;
+    }
+
+    let mut timings: Vec<f64> = (0..iterations)
+        .map(|_| {
+            let start = Instant::now();
+            let _ = std::hint::black_box(run());
+            elapsed_ms(start)
+        })
+        .collect();
+    timings.sort_by(f64::total_cmp);
+
+    // Nearest-rank percentile over the sorted timings
+    let percentile = |p: f64| {
+        let rank = (p / 100.0 * timings.len() as f64).ceil() as usize;
+        timings[rank.clamp(1, timings.len()) - 1]
+    };
+    json!({
+        "success": true,
+        "op": op,
+        "iterations": iterations,
+        "min_ms": timings[0],
+        "max_ms": timings[timings.len() - 1],
+        "mean_ms": timings.iter().sum::<f64>() / timings.len() as f64,
+        "p50_ms": percentile(50.0),
+        "p99_ms": percentile(99.0)
+    })
+}
+
+/// Encode a JSON value and decode the bytes straight back, for /transcode
+///
+/// The request's encode and decode options both apply. `lossless` is structural JSON
+/// equality of input and output, so a marker the decoder writes differently (or an
+/// integer that comes back as a float) shows up as a loss.
+pub fn transcode_json(value: JsonValue, json: &JsonValue) -> JsonValue {
+    let start = Instant::now();
+    let options = match DecodeOptions::from_request(json)
+        .and_then(|decode| Ok((decode, EncodeOptions::from_request(json)?)))
+    {
+        Ok(options) => options,
+        Err(e) => return e,
+    };
+    let (decode_options, encode_options) = options;
+
+    let bytes = match encode_cbor_value(value.clone(), &encode_options) {
+        Ok(b) => b,
+        Err(e) => return e,
+    };
+    let result = match read_item(&bytes, &mask_simple_values(&bytes)).and_then(|(decoded, _)| {
+        cbor_to_json(decoded, &mut DecodeState::scan(&bytes, decode_options))
+    }) {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+
+    json!({
+        "success": true,
+        "hex": encode_options.encoding.encode(&bytes),
+        "result": result,
+        "lossless": result == value,
+        "duration_ms": elapsed_ms(start)
+    })
+}
+
+/// Options accepted alongside "value" in an /encode request
+pub struct EncodeOptions {
+    /// Sort map keys into canonical order
+    pub canonical: bool,
+    /// Which canonical key order to sort into
+    pub canonical_mode: CanonicalMode,
+    /// Write each float in the narrowest width that preserves its value
+    pub float_shrink: bool,
+    /// Deepest nesting of arrays and objects accepted in the input value
+    pub depth_limit: usize,
+    /// How the output bytes are written into the "hex" response field
+    pub encoding: PayloadEncoding,
+    /// Tag number wrapping the whole encoded value
+    pub tag: Option<u64>,
+    /// Fail unless every float can be written in half precision without losing its value
+    pub force_half: bool,
+    /// List what the input holds that the CBOR output cannot carry as `warnings`
+    pub warnings: bool,
+    /// Major type the encoded item must have, checked after encoding
+    pub expect_major: Option<u8>,
+    /// Describe the first headers of the output in an `explain` list
+    pub explain: bool,
+    /// End an /encode-seq sequence with a 0xff break byte; other endpoints ignore it
+    ///
+    /// Not part of RFC 8742: a standard CBOR sequence is just the concatenated items, and a
+    /// lone break is not a well-formed item, so decoders of plain sequences will reject it.
+    /// It exists for protocols that delimit sequences this way.
+    pub break_terminated: bool,
+    /// Encode JSON strings holding an integer as CBOR integers rather than text
+    ///
+    /// On by default, matching the large-integer strings the decoder emits; turn it off so
+    /// decoded text such as "12" re-encodes as text.
+    pub integer_strings: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            canonical: false,
+            canonical_mode: CanonicalMode::Bytewise,
+            float_shrink: false,
+            depth_limit: MAX_DEPTH,
+            encoding: PayloadEncoding::Hex,
+            tag: None,
+            force_half: false,
+            warnings: false,
+            expect_major: None,
+            explain: false,
+            break_terminated: false,
+            integer_strings: true,
+        }
+    }
+}
+
+impl EncodeOptions {
+    pub fn from_request(json: &JsonValue) -> Result<Self, JsonValue> {
+        Ok(EncodeOptions {
+            // Naming a mode asks for canonical output in that mode
+            canonical: json
+                .get("canonical")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(json.get("canonical_mode").is_some()),
+            canonical_mode: CanonicalMode::from_request(json)?,
+            float_shrink: json
+                .get("float_shrink")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            depth_limit: depth_limit_from_request(json),
+            encoding: PayloadEncoding::from_request(json)?,
+            tag: json.get("tag").and_then(|v| v.as_u64()),
+            force_half: json
+                .get("force_half")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            warnings: json
+                .get("warnings")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            expect_major: match json.get("expect_major").and_then(|v| v.as_str()) {
+                Some(name) => Some(major_from_name(name).ok_or_else(|| {
+                    error_response(
+                        ErrorCode::UnknownMajor,
+                        format!(
+                            "Unknown major type: {} (expected one of {})",
+                            name,
+                            MAJOR_TYPE_NAMES.join(", ")
+                        ),
+                    )
+                })?),
+                None => None,
+            },
+            explain: json
+                .get("explain")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            break_terminated: json
+                .get("break_terminated")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            integer_strings: json
+                .get("integer_strings")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(true),
+        })
+    }
+}
+
+/// Names of the CBOR major types 0-7, as accepted by expect_major
+const MAJOR_TYPE_NAMES: [&str; 8] = [
+    "uint", "nint", "bytes", "text", "array", "map", "tag", "simple",
+];
+
+/// Major type for a name in MAJOR_TYPE_NAMES, or one of the type_histogram spellings
+fn major_from_name(name: &str) -> Option<u8> {
+    let name = match name {
+        "bstr" => "bytes",
+        "tstr" => "text",
+        "float" => "simple",
+        other => other,
+    };
+    MAJOR_TYPE_NAMES
+        .iter()
+        .position(|&major| major == name)
+        .and_then(|major| u8::try_from(major).ok())
+}
+
+/// Encode value to CBOR hex string
+pub fn encode_cbor(value: JsonValue, options: &EncodeOptions) -> JsonValue {
+    let start = Instant::now();
+
+    // Compact serialization, so the size delta is against the smallest JSON form
+    let input_json_bytes = value.to_string().len();
+    let mut warnings = Vec::new();
+    if options.warnings {
+        encode_warnings(&value, &mut warnings);
+    }
+
+    let encode_start = Instant::now();
+    let bytes = match encode_cbor_value(value, options) {
+        Ok(b) => b,
+        Err(e) => return e,
+    };
+    let cbor_encode_ms = elapsed_ms(encode_start);
+
+    let hex_start = Instant::now();
+    let hex_string = options.encoding.encode(&bytes);
+    let hex_encode_ms = elapsed_ms(hex_start);
+    let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let mut response = json!({
+        "success": true,
+        "hex": hex_string,
+        "duration_ms": duration_ms,
+        "cbor_encode_ms": cbor_encode_ms,
+        "hex_encode_ms": hex_encode_ms,
+        "input_json_bytes": input_json_bytes,
+        "output_cbor_bytes": bytes.len()
+    });
+    if options.warnings {
+        response["warnings"] = json!(warnings);
+    }
+    if options.explain {
+        response["explain"] = json!(explain_headers(&bytes));
+    }
+    response
+}
+
+/// One line per header for the first EXPLAIN_HEADERS headers of an item, in wire order
+///
+/// Each line shows the header bytes, then what they mean, e.g. `a2 = map, major 5, 2 items`.
+/// String contents are not listed, only the headers that give their length.
+fn explain_headers(bytes: &[u8]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let _ = scan_headers(bytes, &mut |header, _| {
+        if lines.len() >= EXPLAIN_HEADERS {
+            return;
+        }
+        let len = match header.info {
+            24 => 2,
+            25 => 3,
+            26 => 5,
+            27 => 9,
+            _ => 1,
+        };
+        let header_hex: Vec<String> = bytes[header.offset..header.offset + len]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        lines.push(format!(
+            "{} = {}",
+            header_hex.join(" "),
+            explain_header(header)
+        ));
+    });
+    lines
+}
+
+/// Long names of major types 0-6 for explain_header
+const MAJOR_KINDS: [&str; 7] = [
+    "unsigned integer",
+    "negative integer",
+    "byte string",
+    "text string",
+    "array",
+    "map",
+    "tag",
+];
+
+/// What a single header means, e.g. `text string, major 3, 5 bytes`
+fn explain_header(header: &RawHeader) -> String {
+    let count = |n: u64, unit: &str| format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" });
+    let detail = match (header.major, header.arg) {
+        (0, Some(n)) => format!("value {}", n),
+        (1, Some(n)) => format!("value {}", -1 - i128::from(n)),
+        (2 | 3, Some(n)) => count(n, "byte"),
+        (4 | 5, Some(n)) => count(n, "item"),
+        (6, Some(n)) => format!("tag {}", n),
+        (7, _) => return format!("{}, major 7", explain_simple(header)),
+        _ => "indefinite length".to_string(),
+    };
+    format!(
+        "{}, major {}, {}",
+        MAJOR_KINDS[usize::from(header.major)],
+        header.major,
+        detail
+    )
+}
+
+/// What a major type 7 header holds: a simple value, a float or the break code
+fn explain_simple(header: &RawHeader) -> String {
+    match (header.info, header.arg) {
+        (20, _) => "false".to_string(),
+        (21, _) => "true".to_string(),
+        (22, _) => "null".to_string(),
+        (23, _) => "undefined".to_string(),
+        (25, Some(bits)) => format!("half-precision float {}", f16::from_bits(bits as u16)),
+        (26, Some(bits)) => format!("single-precision float {}", f32::from_bits(bits as u32)),
+        (27, Some(bits)) => format!("double-precision float {}", f64::from_bits(bits)),
+        (31, _) => "break".to_string(),
+        (_, Some(n)) => format!("simple value {}", n),
+        (_, None) => "simple value".to_string(),
+    }
+}
+
+/// Describe what in a JSON input value cannot carry over into CBOR as intended
+fn encode_warnings(value: &JsonValue, warnings: &mut Vec<String>) {
+    match value {
+        JsonValue::Number(n) if n.is_f64() => {
+            // serde_json reads integers beyond the i64/u64 range as floats
+            let f = n.as_f64().unwrap_or_default();
+            if f.fract() == 0.0 && !(-(2f64.powi(63))..2f64.powi(64)).contains(&f) {
+                warnings.push(format!(
+                    "whole number {} is beyond the 64-bit integer range and encoded as a float",
+                    n
+                ));
+            }
+        }
+        JsonValue::Array(items) => items.iter().for_each(|v| encode_warnings(v, warnings)),
+        JsonValue::Object(obj) => {
+            if let Some(content) = obj.get("__cbor_bytes__") {
+                if bytes_from_marker(obj).is_none() {
+                    warnings.push(format!(
+                        "__cbor_bytes__ value {} is not valid in its encoding, so the object is encoded as a map",
+                        content
+                    ));
+                }
+            }
+            obj.values().for_each(|v| encode_warnings(v, warnings));
+        }
+        _ => {}
+    }
+}
+
+/// Encode each value and concatenate them into a CBOR sequence (RFC 8742)
+pub fn encode_cbor_seq(values: Vec<JsonValue>, options: &EncodeOptions) -> JsonValue {
+    let start = Instant::now();
+
+    let mut bytes = Vec::new();
+    let mut offsets = Vec::with_capacity(values.len());
+    for (index, value) in values.into_iter().enumerate() {
+        match encode_cbor_value(value, options) {
+            Ok(item) => {
+                offsets.push(bytes.len());
+                bytes.extend_from_slice(&item);
+            }
+            Err(mut e) => {
+                let message = e["error"].as_str().unwrap_or_default();
+                e["error"] = json!(format!("item {}: {}", index, message));
+                return e;
+            }
+        }
+    }
+    if options.break_terminated {
+        bytes.push(0xff);
+    }
+
+    let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    json!({
+        "success": true,
+        "hex": options.encoding.encode(&bytes),
+        "offsets": offsets,
+        "duration_ms": duration_ms
+    })
+}
+
+/// Encode a JSON value to CBOR bytes, returning an error response on failure
+pub fn encode_cbor_value(value: JsonValue, options: &EncodeOptions) -> Result<Vec<u8>, JsonValue> {
+    if json_depth(&value) > options.depth_limit {
+        return Err(error_response(
+            ErrorCode::DepthExceeded,
+            "max depth exceeded",
+        ));
+    }
+
+    let mut bytes = Vec::new();
+    if let Some(tag) = options.tag {
+        write_header(&mut bytes, 6, tag);
+    }
+    write_json_cbor(value, options, &mut bytes)
+        .map_err(|e| error_response(ErrorCode::CborEncode, format!("CBOR encode error: {}", e)))?;
+    if options.force_half {
+        check_half_floats(&bytes)?;
+    }
+    if let Some(expected) = options.expect_major {
+        // Encoding always writes at least one byte, whose top three bits are the major type
+        let major = bytes.first().map_or(expected, |initial| initial >> 5);
+        if major != expected {
+            return Err(error_response(
+                ErrorCode::MajorMismatch,
+                format!(
+                    "encoded a {} where {} was expected",
+                    MAJOR_TYPE_NAMES[usize::from(major)],
+                    MAJOR_TYPE_NAMES[usize::from(expected)]
+                ),
+            ));
+        }
+    }
+    Ok(bytes)
+}
+
+/// Reject encoded output holding any float wider than half precision, for force_half
+///
+/// Both encode paths write floats in their shortest exact form, so a wider float is one
+/// half precision cannot represent, or one a `__cbor_float_width__` marker pinned wider.
+fn check_half_floats(bytes: &[u8]) -> Result<(), JsonValue> {
+    let mut wide = None;
+    let _ = scan_headers(bytes, &mut |header, _| {
+        let value = match (float_width(header), header.arg) {
+            (Some(32), Some(bits)) => f64::from(f32::from_bits(bits as u32)),
+            (Some(64), Some(bits)) => f64::from_bits(bits),
+            _ => return,
+        };
+        wide.get_or_insert((value, header.offset));
+    });
+
+    match wide {
+        None => Ok(()),
+        Some((value, offset)) if exact_half(value).is_none() => Err(error_response(
+            ErrorCode::CborEncode,
+            format!(
+                "float {} at offset {} is not exactly representable in half precision",
+                value, offset
+            ),
+        )),
+        Some((value, offset)) => Err(error_response(
+            ErrorCode::CborEncode,
+            format!(
+                "float {} at offset {} is pinned wider than half precision",
+                value, offset
+            ),
+        )),
+    }
+}
+
+/// Encode diagnostic notation text to CBOR hex string
+pub fn encode_diag(diag: &str) -> JsonValue {
+    let start = Instant::now();
+
+    let (value, nulls) = match parse_diag_simples(diag) {
+        Ok(parsed) => parsed,
+        Err(e) => return e,
+    };
+
+    let mut written = Vec::new();
+    if let Err(e) = ciborium::into_writer(&value, &mut written) {
+        return error_response(ErrorCode::CborEncode, format!("CBOR encode error: {}", e));
+    }
+    let mut nulls = nulls.into_iter();
+    let bytes = unmask_simple_values(&written, || nulls.next().unwrap_or(SIMPLE_NULL));
+
+    let hex_string = hex::encode(&bytes);
+    let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    json!({
+        "success": true,
+        "hex": hex_string,
+        "duration_ms": duration_ms
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode the "hex" of a /decode request body with the options it carries
+    fn decode(request: JsonValue) -> JsonValue {
+        let options = DecodeOptions::from_request(&request).unwrap();
+        decode_cbor(request["hex"].as_str().unwrap(), &options)
+    }
+
+    /// Encode the "value" of an /encode request body with the options it carries
+    fn encode(request: JsonValue) -> JsonValue {
+        let options = EncodeOptions::from_request(&request).unwrap();
+        encode_cbor(request["value"].clone(), &options)
+    }
+
+    fn canonical_hex(value: JsonValue) -> Result<String, JsonValue> {
+        let options = EncodeOptions {
+            canonical: true,
+            ..EncodeOptions::default()
+        };
+        encode_cbor_value(value, &options).map(hex::encode)
+    }
+
+    fn diag(hex: &str) -> JsonValue {
+        diagnostic_cbor(hex)["result"].clone()
+    }
+
+    #[test]
+    fn integer_strings_above_i64_encode_as_unsigned() {
+        let result = encode(json!({"value": "18446744073709551615"}));
+        assert_eq!(result["hex"], "1bffffffffffffffff");
+        let result = encode(json!({"value": ["9223372036854775807", "9223372036854775808"]}));
+        assert_eq!(result["hex"], "821b7fffffffffffffff1b8000000000000000");
+    }
+
+    #[test]
+    fn diagnostic_renders_nested_maps_and_tags() {
+        // {"a": {"b": [1, h'0102']}, 1: -1}
+        assert_eq!(
+            diag("a26161a1616282014201020120"),
+            r#"{"a": {"b": [1, h'0102']}, 1: -1}"#
+        );
+        // 1(1363896240.5) and 24(h'0a') inside an array, tags nested in a tag
+        assert_eq!(
+            diag("82c1fb41d452d9ec200000d818410a"),
+            "[1(1363896240.5), 24(h'0a')]"
+        );
+        assert_eq!(diag("c0c100"), "0(1(0))");
+        assert_eq!(diag("f97e00"), "NaN");
+        assert_eq!(diag("fa47c35000"), "100000.0");
+    }
+
+    #[test]
+    fn diagnostic_simple_values_round_trip_through_hex() {
+        for (text, hex) in [
+            ("[undefined, simple(16), simple(255), null]", "84f7f0f8fff6"),
+            ("{undefined: 24(simple(16))}", "a1f7d818f0"),
+            ("[null, undefined, simple(0), true]", "84f6f7e0f5"),
+        ] {
+            assert_eq!(encode_diag(text)["hex"], hex, "{}", text);
+            assert_eq!(diag(hex), text);
+        }
+        // simple(20) and simple(21) are false and true; 24..=31 are not simple values
+        assert_eq!(encode_diag("[simple(20), simple(21)]")["hex"], "82f4f5");
+        for text in ["simple(24)", "simple(31)", "simple(256)", "simple()"] {
+            assert_eq!(
+                encode_diag(text)["error_code"],
+                "invalid_diagnostic",
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn canonical_orders_keys_by_encoded_bytes() {
+        // JSON objects arrive sorted by key, which already matches for same-length keys
+        let plain = encode(json!({"value": {"b": 1, "a": 2}}));
+        let canonical = encode(json!({"value": {"b": 1, "a": 2}, "canonical": true}));
+        assert_eq!(plain["hex"], "a2616102616201");
+        assert_eq!(canonical["hex"], plain["hex"]);
+
+        // The shorter key encodes smaller, though "aa" sorts first as a string
+        let plain = encode(json!({"value": {"aa": 1, "b": 2}}));
+        let canonical = encode(json!({"value": {"aa": 1, "b": 2}, "canonical": true}));
+        assert_eq!(plain["hex"], "a262616101616202");
+        assert_eq!(canonical["hex"], "a261620262616101");
+    }
+
+    #[test]
+    fn undefined_round_trips() {
+        let decoded = decode(json!({"hex": "f7"}));
+        assert_eq!(decoded["result"], json!({"__cbor_undefined__": true}));
+        let encoded = encode(json!({"value": decoded["result"]}));
+        assert_eq!(encoded["hex"], "f7");
+
+        // Null stays null
+        assert_eq!(decode(json!({"hex": "f6"}))["result"], JsonValue::Null);
+        let decoded = decode(json!({"hex": "82f6f7"}));
+        assert_eq!(
+            decoded["result"],
+            json!([null, {"__cbor_undefined__": true}])
+        );
+    }
+
+    #[test]
+    fn parse_diag_reads_nested_tags_and_spaced_hex() {
+        assert_eq!(encode_diag("1(2(h'01 02'))")["hex"], "c1c2420102");
+        assert_eq!(encode_diag("h' 01\n02\t03 '")["hex"], "43010203");
+        assert_eq!(
+            encode_diag(r#"[1, 2(h'01'), {"a": 24(h'0a 0b')}]"#)["hex"],
+            "8301c24101a16161d818420a0b"
+        );
+        assert_eq!(
+            parse_diag("0(1(2(3)))"),
+            Ok(Value::Tag(
+                0,
+                Box::new(Value::Tag(
+                    1,
+                    Box::new(Value::Tag(2, Box::new(Value::Integer(3.into()))))
+                ))
+            ))
+        );
+    }
+
+    #[test]
+    fn strict_maps_rejects_duplicate_keys() {
+        // {"a": 1, "a": 2}
+        let hex = "a2616101616102";
+        let lenient = decode(json!({"hex": hex}));
+        assert_eq!(lenient["success"], true);
+        assert_eq!(lenient["result"], json!({"a": 2}));
+
+        let strict = decode(json!({"hex": hex, "strict_maps": true}));
+        assert_eq!(strict["success"], false);
+        assert_eq!(strict["error"], "duplicate map key: a");
+    }
+
+    #[test]
+    fn float_shrink_picks_the_shortest_exact_width() {
+        let shrunk =
+            |value: JsonValue| encode(json!({"value": value, "float_shrink": true}))["hex"].clone();
+        assert_eq!(shrunk(json!(1.5)), "f93e00");
+        assert_eq!(shrunk(json!(100000.5)), "fa47c35040");
+        assert_eq!(shrunk(json!(1.1)), "fb3ff199999999999a");
+        // Without the flag a float marker is spelled out as a double
+        let marker = json!({"__cbor_float__": 1.5});
+        assert_eq!(
+            encode(json!({"value": marker}))["hex"],
+            "fb3ff8000000000000"
+        );
+        assert_eq!(shrunk(marker), "f93e00");
+    }
+
+    #[test]
+    fn deep_nesting_is_rejected_cleanly() {
+        let hex = format!("{}00", "81".repeat(10_000));
+        let decoded = decode(json!({ "hex": hex }));
+        assert_eq!(decoded["success"], false);
+        assert_eq!(decoded["error"], "max depth exceeded");
+
+        // serde_json stops at 128 levels, so deeper JSON can only be built in code
+        let mut value = json!(0);
+        for _ in 0..=MAX_DEPTH {
+            value = JsonValue::Array(vec![value]);
+        }
+        let encoded = encode(json!({ "value": value }));
+        assert_eq!(encoded["success"], false);
+        assert_eq!(encoded["error"], "max depth exceeded");
+
+        // The limit is configurable below the default
+        let shallow = decode(json!({"hex": "818100", "depth_limit": 1}));
+        assert_eq!(shallow["error_code"], "depth_exceeded");
+        assert_eq!(decode(json!({"hex": "818100"}))["success"], true);
+    }
+
+    #[test]
+    fn simple_values_round_trip() {
+        let decoded = decode(json!({"hex": "f0"}));
+        assert_eq!(decoded["result"], json!({"__cbor_simple__": 16}));
+        let encoded = encode(json!({"value": decoded["result"]}));
+        assert_eq!(encoded["hex"], "f0");
+
+        // One-byte simple values past the reserved range use the f8 form
+        let decoded = decode(json!({"hex": "82f820f8ff"}));
+        let simple = |n: u8| json!({ "__cbor_simple__": n });
+        assert_eq!(decoded["result"], json!([simple(32), simple(255)]));
+        assert_eq!(
+            encode(json!({"value": decoded["result"]}))["hex"],
+            "82f820f8ff"
+        );
+    }
+
+    #[test]
+    fn preserve_int_keys_tells_int_and_text_keys_apart() {
+        // {1: "a"} and {"1": "a"}
+        let (int_key, text_key) = ("a1016161", "a161316161");
+        assert_eq!(
+            decode(json!({"hex": int_key}))["result"],
+            decode(json!({"hex": text_key}))["result"]
+        );
+
+        let preserved = decode(json!({"hex": int_key, "preserve_int_keys": true}));
+        assert_eq!(
+            preserved["result"],
+            json!({"__cbor_map_entries__": [[1, "a"]]})
+        );
+        let text = decode(json!({"hex": text_key, "preserve_int_keys": true}));
+        assert_eq!(text["result"], json!({"1": "a"}));
+
+        let encoded = encode(json!({ "value": preserved["result"] }));
+        assert_eq!(encoded["hex"], int_key);
+    }
+
+    #[test]
+    fn datetime_tags_round_trip() {
+        let cases = [
+            (
+                "c074323031332d30332d32315432303a30343a30305a",
+                json!({"__cbor_datetime__": "2013-03-21T20:04:00Z"}),
+            ),
+            ("c11a514b67b0", json!({"__cbor_epoch__": 1363896240})),
+            // A float epoch stays a float
+            (
+                "c1fb41d452d9ec200000",
+                json!({"__cbor_epoch__": 1363896240.5}),
+            ),
+        ];
+        for (hex, marker) in cases {
+            assert_eq!(decode(json!({"hex": hex}))["result"], marker);
+            assert_eq!(encode(json!({ "value": marker }))["hex"], hex);
+        }
+    }
+
+    #[test]
+    fn decoded_keys_come_out_sorted() {
+        // {"b": 1, "a": 2}
+        let decoded = decode(json!({"hex": "a2616201616102"}));
+        assert_eq!(decoded["result"].to_string(), r#"{"a":2,"b":1}"#);
+    }
+
+    #[test]
+    fn check_minimal_flags_wide_integers() {
+        let minimal = decode(json!({"hex": "0a", "check_minimal": true}));
+        assert_eq!(minimal["non_minimal"], false);
+        // 10 spelled with a one-byte argument
+        let wide = decode(json!({"hex": "180a", "check_minimal": true}));
+        assert_eq!(wide["result"], 10);
+        assert_eq!(wide["non_minimal"], true);
+        // Lengths count too: a one-element array with a one-byte length
+        let array = decode(json!({"hex": "98010a", "check_minimal": true}));
+        assert_eq!(array["non_minimal"], true);
+        assert!(decode(json!({"hex": "180a"})).get("non_minimal").is_none());
+    }
+
+    #[test]
+    fn tag_option_wraps_the_root() {
+        assert_eq!(
+            encode(json!({"value": [1, 2], "tag": 4}))["hex"],
+            "c4820102"
+        );
+        // Composes with canonical output
+        let sorted = encode(json!({"value": {"b": 1, "a": 2}, "tag": 4, "canonical": true}));
+        assert_eq!(sorted["hex"], "c4a2616102616201");
+    }
+
+    #[test]
+    fn negative_zero_keeps_its_sign() {
+        let decoded = decode(json!({"hex": "f98000"}));
+        assert_eq!(decoded["result"], json!({"__cbor_float__": "-0"}));
+        let encoded = encode(json!({ "value": decoded["result"] }));
+        assert_eq!(encoded["hex"], "f98000");
+        // Positive zero is a plain number
+        assert_eq!(decode(json!({"hex": "f90000"}))["result"], json!(0.0));
+    }
+
+    #[test]
+    fn strict_rejects_reserved_additional_info() {
+        // Major type 0 with additional info 28
+        let strict = decode(json!({"hex": "1c", "strict": true}));
+        assert_eq!(strict["success"], false);
+        assert_eq!(strict["error_code"], "reserved_encoding");
+        // Nested ones are found too
+        let nested = decode(json!({"hex": "82011d", "strict": true}));
+        assert_eq!(nested["error_code"], "reserved_encoding");
+
+        let lenient = decode(json!({"hex": "1c"}));
+        assert_eq!(lenient["success"], false);
+        assert_eq!(lenient["error_code"], "cbor_decode");
+    }
+
+    #[test]
+    fn decode_embedded_unwraps_nested_tag_24() {
+        // 24(h'd8184101'): an encoded item holding an encoded 1
+        let hex = "d81844d8184101";
+        let decoded = decode(json!({"hex": hex, "decode_embedded": true}));
+        assert_eq!(
+            decoded["result"],
+            json!({"__cbor_encoded_cbor__": {"__cbor_encoded_cbor__": 1}})
+        );
+        assert_eq!(encode(json!({ "value": decoded["result"] }))["hex"], hex);
+        // Without the flag the byte string stays opaque
+        let opaque = decode(json!({"hex": hex}));
+        assert!(opaque["result"].get("__cbor_encoded_cbor__").is_none());
+    }
+
+    #[test]
+    fn float_subtype_reports_each_width() {
+        let cases = [
+            ("f93e00", 16),
+            ("fa3fc00000", 32),
+            ("fb3ff8000000000000", 64),
+        ];
+        for (hex, width) in cases {
+            let decoded = decode(json!({"hex": hex, "float_subtype": true}));
+            assert_eq!(
+                decoded["result"],
+                json!({"__cbor_float_width__": width, "value": 1.5})
+            );
+            assert_eq!(encode(json!({ "value": decoded["result"] }))["hex"], hex);
+        }
+        // Widths follow the floats in wire order
+        let decoded = decode(json!({"hex": "82fa3fc00000f93e00", "float_subtype": true}));
+        assert_eq!(
+            decoded["result"],
+            json!([
+                {"__cbor_float_width__": 32, "value": 1.5},
+                {"__cbor_float_width__": 16, "value": 1.5}
+            ])
+        );
+    }
+
+    #[test]
+    fn decimal_fractions_and_bigfloats_round_trip() {
+        let cases = [
+            // 273.15 as 27315e-2
+            (
+                "c48221196ab3",
+                json!({"__cbor_decimal__": {"exp": -2, "mantissa": 27315}}),
+            ),
+            // 1.5 as 3 * 2^-1
+            (
+                "c5822003",
+                json!({"__cbor_bigfloat__": {"exp": -1, "mantissa": 3}}),
+            ),
+            // A bignum mantissa, 2^64 * 2^-1
+            (
+                "c58220c249010000000000000000",
+                json!({"__cbor_bigfloat__": {
+                    "exp": -1,
+                    "mantissa": {"__cbor_bignum__": "18446744073709551616"}
+                }}),
+            ),
+        ];
+        for (hex, marker) in cases {
+            assert_eq!(decode(json!({"hex": hex}))["result"], marker);
+            assert_eq!(encode(json!({ "value": marker }))["hex"], hex);
+        }
+    }
+
+    #[test]
+    fn ordered_keeps_reverse_sorted_keys() {
+        // {"c": 1, "b": 2, "a": 3}
+        let hex = "a3616301616202616103";
+        let decoded = decode(json!({"hex": hex, "ordered": true}));
+        assert_eq!(
+            decoded["result"],
+            json!({"__cbor_ordered_map__": [["c", 1], ["b", 2], ["a", 3]]})
+        );
+        assert_eq!(encode(json!({ "value": decoded["result"] }))["hex"], hex);
+        // A plain object is written in sorted order instead
+        let plain = decode(json!({"hex": hex}));
+        assert_eq!(
+            encode(json!({ "value": plain["result"] }))["hex"],
+            "a3616103616202616301"
+        );
+    }
+
+    #[test]
+    fn encode_seq_concatenates_items() {
+        let options = EncodeOptions::default();
+        let encoded = encode_cbor_seq(vec![json!(1), json!(2), json!(3)], &options);
+        assert_eq!(encoded["hex"], "010203");
+        assert_eq!(encoded["offsets"], json!([0, 1, 2]));
+
+        let mixed = encode_cbor_seq(vec![json!("ab"), json!(1000), json!([])], &options);
+        assert_eq!(mixed["hex"], "6261621903e880");
+        assert_eq!(mixed["offsets"], json!([0, 3, 6]));
+
+        let failed = encode_cbor_seq(vec![json!(1), json!({"__cbor_simple__": 24})], &options);
+        assert_eq!(failed["success"], false);
+        assert!(failed["error"].as_str().unwrap().starts_with("item 1: "));
+    }
+
+    #[test]
+    fn nan_bits_keep_signaling_payloads() {
+        // Quiet bit clear: signaling NaNs in half and single precision
+        for bits in ["7d00", "7fa00000"] {
+            let hex = format!("{}{}", if bits.len() == 4 { "f9" } else { "fa" }, bits);
+            let decoded = decode(json!({"hex": hex, "nan_bits": true}));
+            assert_eq!(
+                decoded["result"],
+                json!({"__cbor_float__": "NaN", "__cbor_nan_bits__": bits})
+            );
+            assert_eq!(encode(json!({ "value": decoded["result"] }))["hex"], hex);
+            // Without the flag only the NaN itself is reported
+            let plain = decode(json!({ "hex": hex }));
+            assert_eq!(plain["result"], json!({"__cbor_float__": "NaN"}));
+        }
+    }
+
+    #[test]
+    fn stats_counts_major_types() {
+        // {"a": [1, 2], "b": [], "c": 24(h'0102')}
+        let decoded = decode(json!({"hex": "a361618201026162806163d818420102", "stats": true}));
+        assert_eq!(
+            decoded["type_histogram"],
+            json!({"array": 2, "bstr": 1, "map": 1, "tag": 1, "tstr": 3, "uint": 2})
+        );
+        assert!(decode(json!({"hex": "01"})).get("type_histogram").is_none());
+    }
+
+    #[test]
+    fn empty_byte_and_text_strings_stay_apart() {
+        assert_eq!(
+            encode(json!({"value": {"__cbor_bytes__": ""}}))["hex"],
+            "40"
+        );
+        assert_eq!(encode(json!({"value": ""}))["hex"], "60");
+        assert_eq!(
+            decode(json!({"hex": "40"}))["result"],
+            json!({"__cbor_bytes__": ""})
+        );
+        assert_eq!(decode(json!({"hex": "60"}))["result"], "");
+    }
+
+    #[test]
+    fn integer_strings_cover_the_cbor_range() {
+        let cases = [
+            ("9223372036854775808", "1b8000000000000000"),
+            ("18446744073709551615", "1bffffffffffffffff"),
+            ("-18446744073709551616", "3bffffffffffffffff"),
+            ("-5", "24"),
+            // Past the native range, or not an integer at all, the string stays text
+            (
+                "18446744073709551616",
+                "743138343436373434303733373039353531363136",
+            ),
+            ("12abc", "653132616263"),
+        ];
+        for (string, hex) in cases {
+            assert_eq!(encode(json!({ "value": string }))["hex"], hex, "{}", string);
+        }
+    }
+
+    #[test]
+    fn force_half_requires_exact_halves() {
+        let half = |value: JsonValue| encode(json!({"value": value, "force_half": true}));
+        assert_eq!(half(json!(1.0))["hex"], "f93c00");
+        assert_eq!(half(json!([1.5, 2]))["hex"], "82f93e0002");
+
+        let inexact = half(json!(0.1));
+        assert_eq!(inexact["success"], false);
+        assert!(inexact["error"]
+            .as_str()
+            .unwrap()
+            .contains("not exactly representable in half precision"));
+    }
+
+    #[test]
+    fn trailing_bytes_are_counted_and_optionally_rejected() {
+        // The integer 10 followed by two junk bytes
+        let decoded = decode(json!({"hex": "0a0102"}));
+        assert_eq!(decoded["success"], true);
+        assert_eq!(decoded["result"], 10);
+        assert_eq!(decoded["trailing_bytes"], 2);
+
+        let strict = decode(json!({"hex": "0a0102", "reject_trailing": true}));
+        assert_eq!(strict["success"], false);
+        assert_eq!(strict["error_code"], "trailing_bytes");
+        assert_eq!(strict["trailing_bytes"], 2);
+
+        assert_eq!(decode(json!({"hex": "0a"}))["trailing_bytes"], 0);
+    }
+
+    #[test]
+    fn text_marker_keeps_strings_as_text() {
+        // Hex-looking text stays text; only the bytes marker makes a byte string
+        for (value, hex) in [
+            (json!("deadbeef"), "686465616462656566"),
+            (json!({"__cbor_bytes__": "deadbeef"}), "44deadbeef"),
+            (json!({"__cbor_text__": "deadbeef"}), "686465616462656566"),
+        ] {
+            assert_eq!(encode(json!({ "value": value }))["hex"], hex, "{}", value);
+        }
+        for hex in ["686465616462656566", "44deadbeef"] {
+            let decoded = decode(json!({ "hex": hex }));
+            assert_eq!(encode(json!({"value": decoded["result"]}))["hex"], hex);
+        }
+
+        // An integer-looking string needs the marker to stay text
+        assert_eq!(encode(json!({"value": "12"}))["hex"], "0c");
+        let marked = encode(json!({"value": {"__cbor_text__": "12"}}));
+        assert_eq!(marked["hex"], "623132");
+        assert_eq!(decode(json!({"hex": "623132"}))["result"], "12");
+
+        // Without integer_strings, decoded text round-trips as plain strings at any depth
+        for hex in ["623132", "82623132632d3130", "81d864a16161623132"] {
+            let decoded = decode(json!({ "hex": hex }));
+            let request = json!({"value": decoded["result"], "integer_strings": false});
+            assert_eq!(encode(request)["hex"], hex);
+        }
+        // Explicit integer markers still read their strings
+        let marked = json!({
+            "value": [{"__cbor_int__": "12"}, {"__cbor_int_map__": {"1": "2"}}],
+            "integer_strings": false
+        });
+        assert_eq!(encode(marked)["hex"], "820ca1016132");
+    }
+
+    #[test]
+    fn int_width_marker_writes_each_width() {
+        let cases = [
+            (10, 1, "180a"),
+            (10, 2, "19000a"),
+            (10, 4, "1a0000000a"),
+            (10, 8, "1b000000000000000a"),
+            (-10, 8, "3b0000000000000009"),
+        ];
+        for (int, width, hex) in cases {
+            let marker = json!({"__cbor_int__": int, "__cbor_int_width__": width});
+            assert_eq!(encode(json!({ "value": marker }))["hex"], hex);
+        }
+
+        let error = encode(json!({"value": {"__cbor_int__": 10, "__cbor_int_width__": 3}}));
+        assert_eq!(error["success"], false);
+        assert!(error["error"]
+            .as_str()
+            .unwrap()
+            .contains("invalid integer width 3"));
+        // A value too wide for the requested width is refused rather than truncated
+        let error = encode(json!({"value": {"__cbor_int__": 500, "__cbor_int_width__": 1}}));
+        assert_eq!(error["success"], false);
+    }
+
+    #[test]
+    fn numeric_float_marker_forces_a_float() {
+        let float = |marker: JsonValue| encode(json!({ "value": {"__cbor_float__": marker} }));
+        assert_eq!(float(json!(2))["hex"], "fb4000000000000000");
+        assert_eq!(encode(json!({"value": 2}))["hex"], "02");
+        // The string spellings still work
+        assert_eq!(float(json!("NaN"))["hex"], "f97e00");
+        assert_eq!(float(json!("-Infinity"))["hex"], "f9fc00");
+    }
+
+    #[test]
+    fn indefinite_string_markers_write_chunks() {
+        let cases = [
+            (
+                json!({"__cbor_indefinite_bytes__": ["01", "02"]}),
+                "5f41014102ff",
+            ),
+            (
+                json!({"__cbor_indefinite_text__": ["he", "llo"]}),
+                "7f626865636c6c6fff",
+            ),
+            (json!({"__cbor_indefinite_text__": []}), "7fff"),
+        ];
+        for (marker, hex) in cases {
+            assert_eq!(encode(json!({ "value": marker }))["hex"], hex);
+        }
+        // Decoders join the chunks
+        assert_eq!(
+            decode(json!({"hex": "7f626865636c6c6fff"}))["result"],
+            "hello"
+        );
+    }
+
+    #[test]
+    fn hex_input_accepts_prefix_and_case() {
+        for input in ["0xDEAD", "0XDEAD", "DEAD", "dead"] {
+            assert_eq!(decode_hex(input).unwrap(), [0xde, 0xad], "{}", input);
+        }
+        // 1000, spelled the way an upstream tool would
+        let decoded = decode(json!({"hex": "0x1903E8"}));
+        assert_eq!(decoded["result"], 1000);
+        let error = decode(json!({"hex": "0xdea"}));
+        assert_eq!(error["error_code"], "invalid_hex");
+        assert!(error["error"]
+            .as_str()
+            .unwrap()
+            .contains("odd number of digits"));
+        // Output hex stays lowercase without a prefix
+        assert_eq!(
+            encode(json!({"value": {"__cbor_bytes__": "DEAD"}}))["hex"],
+            "42dead"
+        );
+    }
+
+    #[test]
+    fn canonical_modes_order_keys_of_different_lengths() {
+        // Keys 10 (0a), 256 (190100) and -1 (20)
+        let value = json!({"__cbor_map_entries__": [[10, 1], [256, 2], [-1, 3]]});
+        let ordered =
+            |mode: &str| encode(json!({"value": value, "canonical_mode": mode}))["hex"].clone();
+        // Shorter keys first, then bytewise among equal lengths
+        assert_eq!(ordered("length_first"), "a30a01200319010002");
+        // Plain bytewise: 0x19 sorts before 0x20
+        assert_eq!(ordered("bytewise"), "a30a01190100022003");
+    }
+
+    #[test]
+    fn offsets_locate_each_item() {
+        // {"a": 1, "b": [false, 3]}
+        let decoded = decode(json!({"hex": "a2616101616282f403", "offsets": true}));
+        assert_eq!(decoded["result"], json!({"a": 1, "b": [false, 3]}));
+        assert_eq!(
+            decoded["offsets"],
+            json!({
+                "offset": 0,
+                "len": 9,
+                "entries": [
+                    {"key": {"offset": 1, "len": 2}, "value": {"offset": 3, "len": 1}},
+                    {
+                        "key": {"offset": 4, "len": 2},
+                        "value": {
+                            "offset": 6,
+                            "len": 3,
+                            "items": [{"offset": 7, "len": 1}, {"offset": 8, "len": 1}]
+                        }
+                    }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn preserve_u64_keeps_numbers_at_the_boundaries() {
+        let cases = [
+            ("1b001fffffffffffff", 9007199254740991u64),
+            ("1b0020000000000000", 1 << 53),
+            ("1b8000000000000000", 1 << 63),
+            ("1bffffffffffffffff", u64::MAX),
+        ];
+        for (hex, n) in cases {
+            let preserved = decode(json!({"hex": hex, "preserve_u64": true}));
+            assert_eq!(preserved["result"], json!(n), "{}", hex);
+            // Past 2^53 - 1 the default is a string
+            let expected = if n < 1 << 53 {
+                json!(n)
+            } else {
+                json!(n.to_string())
+            };
+            assert_eq!(decode(json!({ "hex": hex }))["result"], expected, "{}", hex);
+        }
+        // Negative integers are not covered by the flag
+        let negative = decode(json!({"hex": "3b0020000000000000", "preserve_u64": true}));
+        assert_eq!(negative["result"], "-9007199254740993");
+    }
+
+    #[test]
+    fn bytes_encodings_round_trip() {
+        let cases = [
+            ("hex", json!({"__cbor_bytes__": "fbff01"})),
+            (
+                "base64",
+                json!({"__cbor_bytes__": "+/8B", "__cbor_bytes_encoding__": "base64"}),
+            ),
+            (
+                "base64url",
+                json!({"__cbor_bytes__": "-_8B", "__cbor_bytes_encoding__": "base64url"}),
+            ),
+            ("array", json!({"__cbor_bytes__": [251, 255, 1]})),
+        ];
+        for (encoding, marker) in cases {
+            let decoded = decode(json!({"hex": "43fbff01", "bytes_encoding": encoding}));
+            assert_eq!(decoded["result"], marker, "{}", encoding);
+            assert_eq!(encode(json!({ "value": marker }))["hex"], "43fbff01");
+        }
+    }
+
+    #[test]
+    fn hex_dumps_with_whitespace_decode() {
+        assert_eq!(
+            decode_hex("de ad\nbe ef").unwrap(),
+            [0xde, 0xad, 0xbe, 0xef]
+        );
+        assert_eq!(decode_hex("\tde\r\nad ").unwrap(), [0xde, 0xad]);
+        // A two-line dump of [1, 2]
+        assert_eq!(decode(json!({"hex": "82 01\n02"}))["result"], json!([1, 2]));
+
+        let error = decode(json!({"hex": "82 0g"}));
+        assert_eq!(error["error_code"], "invalid_hex");
+    }
+
+    #[test]
+    fn expect_major_catches_marker_typos() {
+        let typo = encode(json!({"value": {"__cbor_byte__": "01"}, "expect_major": "bytes"}));
+        assert_eq!(typo["success"], false);
+        assert_eq!(typo["error_code"], "major_mismatch");
+        assert_eq!(typo["error"], "encoded a map where bytes was expected");
+
+        let bytes = encode(json!({"value": {"__cbor_bytes__": "01"}, "expect_major": "bytes"}));
+        assert_eq!(bytes["hex"], "4101");
+
+        let unknown = EncodeOptions::from_request(&json!({"expect_major": "nope"}));
+        assert_eq!(unknown.err().unwrap()["error_code"], "unknown_major");
+    }
+
+    #[test]
+    fn path_projects_into_the_result() {
+        // {"items": [{"name": "foo"}]}
+        let hex = "a1656974656d7381a1646e616d6563666f6f";
+        let name = decode(json!({"hex": hex, "path": "items.0.name"}));
+        assert_eq!(name["result"], "foo");
+        let item = decode(json!({"hex": hex, "path": "items.0"}));
+        assert_eq!(item["result"], json!({"name": "foo"}));
+
+        let missing = decode(json!({"hex": hex, "path": "items.1"}));
+        assert_eq!(missing["success"], false);
+        assert_eq!(missing["error_code"], "path_not_found");
+        assert_eq!(
+            missing["error"],
+            "path items.1 not found: no '1' under 'items'"
+        );
+    }
+
+    #[test]
+    fn uri_and_base64_tags_round_trip() {
+        let cases = [
+            (
+                "d8207368747470733a2f2f6578616d706c652e636f6d",
+                json!({"__cbor_uri__": "https://example.com"}),
+            ),
+            ("d8216341422d", json!({"__cbor_b64url__": "AB-"})),
+            ("d8226341422b", json!({"__cbor_b64__": "AB+"})),
+        ];
+        for (hex, marker) in cases {
+            assert_eq!(decode(json!({"hex": hex}))["result"], marker);
+            assert_eq!(encode(json!({ "value": marker }))["hex"], hex);
+        }
+
+        // Tag 32 over an integer
+        let error = decode(json!({"hex": "d82001"}));
+        assert_eq!(error["error_code"], "invalid_tag_content");
+        assert_eq!(error["error"], "tag 32 content must be a text string");
+    }
+
+    #[test]
+    fn bench_reports_timing_stats() {
+        for op in ["decode", "encode", "roundtrip"] {
+            let stats = bench_cbor("820102", &json!({"iterations": 5, "op": op}));
+            assert_eq!(stats["success"], true, "{}", op);
+            assert_eq!(stats["op"], op);
+            assert_eq!(stats["iterations"], 5);
+            let [min, p50, p99, max] =
+                ["min_ms", "p50_ms", "p99_ms", "max_ms"].map(|key| stats[key].as_f64().unwrap());
+            assert!(min <= p50 && p50 <= p99 && p99 <= max, "{}", stats);
+            let mean = stats["mean_ms"].as_f64().unwrap();
+            assert!(min <= mean && mean <= max, "{}", stats);
+        }
+
+        let unknown = bench_cbor("820102", &json!({"iterations": 5, "op": "x"}));
+        assert_eq!(unknown["error_code"], "unknown_operation");
+        let zero = bench_cbor("820102", &json!({"iterations": 0}));
+        assert_eq!(zero["error_code"], "invalid_iterations");
+    }
+
+    #[test]
+    fn reject_indefinite_finds_nested_items() {
+        let array = decode(json!({"hex": "9f0102ff", "reject_indefinite": true}));
+        assert_eq!(array["success"], false);
+        assert_eq!(array["error_code"], "indefinite_forbidden");
+        assert_eq!(array["error"], "indefinite-length item (0x9f) at offset 0");
+        // {"a": [_ 1]}
+        let nested = decode(json!({"hex": "a161619f01ff", "reject_indefinite": true}));
+        assert_eq!(nested["error"], "indefinite-length item (0x9f) at offset 3");
+
+        assert_eq!(decode(json!({"hex": "9f0102ff"}))["result"], json!([1, 2]));
+    }
+
+    #[test]
+    fn maps_as_pairs_keeps_mixed_key_types() {
+        // {1: "a", h'01': true, "x": null}
+        let hex = "a30161614101f56178f6";
+        let decoded = decode(json!({"hex": hex, "maps_as_pairs": true}));
+        assert_eq!(
+            decoded["result"],
+            json!({"__cbor_map_entries__": [[1, "a"], [{"__cbor_bytes__": "01"}, true], ["x", null]]})
+        );
+        assert_eq!(encode(json!({ "value": decoded["result"] }))["hex"], hex);
+    }
+
+    #[test]
+    fn check_canonical_compares_encodings_of_one_value() {
+        let canonical = |hex: &str| decode(json!({"hex": hex, "check_canonical": true}));
+        for (deterministic, other) in [
+            // {"a": 1, "b": 2} with its keys swapped
+            ("a2616101616202", "a2616202616101"),
+            // 10 with a one-byte argument
+            ("0a", "180a"),
+            // 1.5 as a double
+            ("f93e00", "fb3ff8000000000000"),
+            // [1] with an indefinite length
+            ("8101", "9f01ff"),
+        ] {
+            let (a, b) = (canonical(deterministic), canonical(other));
+            assert_eq!(a["is_canonical"], true, "{}", deterministic);
+            assert_eq!(b["is_canonical"], false, "{}", other);
+        }
+        assert!(decode(json!({"hex": "0a"})).get("is_canonical").is_none());
+    }
+
+    #[test]
+    fn int_map_marker_writes_integer_keys() {
+        let encoded = encode(json!({"value": {"__cbor_int_map__": {"1": "a", "2": "b"}}}));
+        assert_eq!(encoded["hex"], "a2016161026162");
+        // Keys that are not integers stay text
+        let text = encode(json!({"value": {"__cbor_int_map__": {"x": "a"}}}));
+        assert_eq!(text["hex"], "a161786161");
+        // __cbor_map_entries__ wins over it
+        let both = json!({"__cbor_int_map__": {"1": "a"}, "__cbor_map_entries__": [[2, "b"]]});
+        assert_eq!(encode(json!({ "value": both }))["hex"], "a1026162");
+    }
+
+    #[test]
+    fn max_output_bytes_caps_the_result() {
+        // Ten bytes of input: [1, 2, ... 10]
+        let hex = "8a0102030405060708090a";
+        let capped = decode(json!({"hex": hex, "max_output_bytes": 10}));
+        assert_eq!(capped["success"], false);
+        assert_eq!(capped["error_code"], "output_too_large");
+        assert_eq!(capped["error"], "JSON result exceeds max_output_bytes (10)");
+        // "[1,2,3,4,5,6,7,8,9,10]" is 22 bytes
+        let fits = decode(json!({"hex": hex, "max_output_bytes": 22}));
+        assert_eq!(fits["result"], json!([1, 2, 3, 4, 5, 6, 7, 8, 9, 10]));
+    }
+
+    #[test]
+    fn tag_handlers_can_be_disabled() {
+        // [1(1363896240)]
+        let hex = "81c11a514b67b0";
+        let all = decode(json!({ "hex": hex }));
+        assert_eq!(all["result"], json!([{"__cbor_epoch__": 1363896240}]));
+
+        let raw = decode(json!({"hex": hex, "tag_handlers": ["bignum"]}));
+        assert_eq!(
+            raw["result"],
+            json!([{"__cbor_tag__": 1, "__cbor_value__": 1363896240}])
+        );
+        // Either form re-encodes to the same bytes
+        for decoded in [all, raw] {
+            assert_eq!(encode(json!({ "value": decoded["result"] }))["hex"], hex);
+        }
+
+        let none = decode(json!({"hex": "81c249010000000000000000", "tag_handlers": []}));
+        assert_eq!(
+            none["result"],
+            json!([{"__cbor_tag__": 2, "__cbor_value__": {"__cbor_bytes__": "010000000000000000"}}])
+        );
+
+        let unknown = DecodeOptions::from_request(&json!({"tag_handlers": ["nope"]}));
+        assert_eq!(unknown.err().unwrap()["error_code"], "unknown_tag_handler");
+    }
+
+    #[test]
+    fn transcode_reports_lossless_markers() {
+        for value in [
+            json!({"__cbor_bytes__": "0102"}),
+            json!({"__cbor_float__": "NaN"}),
+            json!({"__cbor_float__": "-Infinity"}),
+            json!([1.5, {"a": {"__cbor_bytes__": ""}}]),
+        ] {
+            let transcoded = transcode_json(value.clone(), &json!({}));
+            assert_eq!(transcoded["lossless"], true, "{}", value);
+            assert_eq!(transcoded["result"], value);
+        }
+        let bytes = transcode_json(json!({"__cbor_bytes__": "0102"}), &json!({}));
+        assert_eq!(bytes["hex"], "420102");
+
+        // A float width only survives when the decode side reports widths
+        let width = json!({"__cbor_float_width__": 32, "value": 1.5});
+        let lossy = transcode_json(width.clone(), &json!({}));
+        assert_eq!(lossy["lossless"], false);
+        assert_eq!(lossy["result"], 1.5);
+        let kept = transcode_json(width, &json!({"float_subtype": true}));
+        assert_eq!(kept["lossless"], true);
+    }
+
+    #[test]
+    fn limit_truncates_each_container() {
+        let big = format!("9903e8{}", "01".repeat(1000));
+        let decoded = decode(json!({"hex": big, "limit": 3}));
+        assert_eq!(
+            decoded["result"],
+            json!([1, 1, 1, {"__cbor_truncated__": true, "remaining": 997}])
+        );
+
+        // The limit applies to every array and map on its own
+        let nested = decode(json!({"hex": "82820102820304", "limit": 1}));
+        assert_eq!(
+            nested["result"],
+            json!([[1, {"__cbor_truncated__": true, "remaining": 1}], {"__cbor_truncated__": true, "remaining": 1}])
+        );
+        let map = decode(json!({"hex": "a3616101616202616303", "limit": 2}));
+        assert_eq!(
+            map["result"],
+            json!({"a": 1, "b": 2, "__cbor_truncated__": true, "__cbor_remaining__": 1})
+        );
+    }
+
+    #[test]
+    fn explain_describes_the_output_headers() {
+        let encoded = encode(json!({"value": {"a": [1, 2]}, "explain": true}));
+        assert_eq!(encoded["hex"], "a16161820102");
+        assert_eq!(
+            encoded["explain"],
+            json!([
+                "a1 = map, major 5, 1 item",
+                "61 = text string, major 3, 1 byte",
+                "82 = array, major 4, 2 items",
+                "01 = unsigned integer, major 0, value 1",
+                "02 = unsigned integer, major 0, value 2"
+            ])
+        );
+        let two = encode(json!({"value": {"a": 1, "b": 2}, "explain": true}));
+        assert_eq!(two["explain"][0], "a2 = map, major 5, 2 items");
+        assert!(encode(json!({"value": 1})).get("explain").is_none());
+    }
+
+    #[test]
+    fn keys_lists_string_and_integer_keys() {
+        // {"a": 1, 2: 3, h'01': 4}
+        let keys = keys_cbor("a36161010203410104");
+        assert_eq!(keys["count"], 3);
+        assert_eq!(
+            keys["keys"],
+            json!(["a", {"key": "2", "type": "uint"}, {"key": "h'01'", "type": "bstr"}])
+        );
+
+        let error = keys_cbor("8101");
+        assert_eq!(error["error_code"], "not_map");
+        assert_eq!(error["error"], "top-level item is array, not a map");
+    }
+
+    #[test]
+    fn cose_unwraps_byte_strings_holding_cbor() {
+        // COSE_Sign1 shape: [h'a10126', {4: h'0102'}, h'68692121', h'0102']
+        let hex = "8443a10126a1044201024468692121420102";
+        let decoded = decode(json!({"hex": hex, "cose": true}));
+        assert_eq!(
+            decoded["result"],
+            json!([
+                {"__cbor_bstr_cbor__": {"1": -7}},
+                {"4": {"__cbor_bytes__": "0102"}},
+                // Not one well-formed item each, so left as bytes
+                {"__cbor_bytes__": "68692121"},
+                {"__cbor_bytes__": "0102"}
+            ])
+        );
+        // Integer header labels need preserve_int_keys to re-encode as integers
+        let exact = decode(json!({"hex": hex, "cose": true, "preserve_int_keys": true}));
+        assert_eq!(
+            exact["result"][0],
+            json!({"__cbor_bstr_cbor__": {"__cbor_map_entries__": [[1, -7]]}})
+        );
+        assert_eq!(encode(json!({ "value": exact["result"] }))["hex"], hex);
+    }
+
+    #[test]
+    fn decode_errors_carry_an_offset() {
+        // {"a": 1, ...} with the second entry missing: input runs out at byte 4
+        let truncated = decode(json!({"hex": "a2616101"}));
+        assert_eq!(truncated["error_code"], "cbor_decode");
+        assert_eq!(truncated["error_offset"], 4);
+        // A reserved header is a syntax error at its own offset
+        let reserved = decode(json!({"hex": "82011c"}));
+        assert_eq!(reserved["error_offset"], 2);
+        // ciborium gives no position for a misplaced break, so there is no offset
+        let stray = decode(json!({"hex": "8201ff"}));
+        assert!(stray.get("error_offset").is_none());
+        // Offsets in a sequence count from its start
+        let options = DecodeOptions::default();
+        assert_eq!(decode_cbor_seq("01a2616101", &options)["error_offset"], 5);
+    }
+
+    #[test]
+    fn public_api_round_trips() {
+        let options = DecodeOptions {
+            preserve_u64: true,
+            ..DecodeOptions::default()
+        };
+        let decoded = decode_cbor_bytes(&decode_hex("0x1bffffffffffffffff").unwrap(), &options);
+        assert_eq!(decoded["result"], json!(u64::MAX));
+
+        let value = json!({"a": [1, -2, "x", {"__cbor_bytes__": "0102"}], "b": null});
+        let bytes = encode_cbor_value(value.clone(), &EncodeOptions::default()).unwrap();
+        let decoded = decode_cbor_bytes(&bytes, &DecodeOptions::default());
+        assert_eq!(decoded["result"], value);
+
+        assert_eq!(encode_diag("[1, h'ff']")["hex"], "820141ff");
+        assert_eq!(roundtrip_cbor("820141ff")["stable"], true);
+        assert_eq!(decode_hex("0xDE AD").unwrap(), [0xde, 0xad]);
+        assert_eq!(decode_hex("abc").unwrap_err()["error_code"], "invalid_hex");
+    }
+
+    #[test]
+    fn float_as_string_gives_stable_strings() {
+        let cases = [
+            ("fb3fb999999999999a", "0.1"),
+            ("fb7e37e43c8800759c", "1e300"),
+            ("f93e00", "1.5"),
+            ("f97c00", "Infinity"),
+        ];
+        for (hex, text) in cases {
+            let decoded = decode(json!({"hex": hex, "float_as_string": true}));
+            assert_eq!(
+                decoded["result"],
+                json!({ "__cbor_float__": text }),
+                "{}",
+                hex
+            );
+            // The string reads back as the same number
+            let encoded = encode(json!({ "value": decoded["result"], "float_shrink": true }));
+            assert_eq!(encoded["hex"], hex);
+        }
+    }
+
+    #[test]
+    fn break_terminated_sequences_end_in_ff() {
+        let options = EncodeOptions::from_request(&json!({"break_terminated": true}));
+        let encoded = encode_cbor_seq(vec![json!(1), json!(2)], &options.ok().unwrap());
+        assert_eq!(encoded["hex"], "0102ff");
+        // The break is not an item
+        assert_eq!(encoded["offsets"], json!([0, 1]));
+
+        let plain = encode_cbor_seq(vec![json!(1), json!(2)], &EncodeOptions::default());
+        assert_eq!(plain["hex"], "0102");
+    }
+
+    #[test]
+    fn parse_diag_limits_nesting() {
+        let nested = |depth: usize| format!("{}1{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse_diag(&nested(MAX_DEPTH)).is_ok());
+        let error = parse_diag(&nested(MAX_DEPTH + 1)).unwrap_err();
+        assert_eq!(error["error_code"], "depth_exceeded");
+        let tags = format!(
+            "{}1{}",
+            "1(".repeat(MAX_DEPTH + 1),
+            ")".repeat(MAX_DEPTH + 1)
+        );
+        assert_eq!(
+            parse_diag(&tags).unwrap_err()["error_code"],
+            "depth_exceeded"
+        );
+        // Far deeper than any stack could recurse
+        let error = parse_diag(&"[".repeat(200_000)).unwrap_err();
+        assert_eq!(error["error_code"], "depth_exceeded");
+    }
+
+    #[test]
+    fn canonical_keeps_markers() {
+        let hex = canonical_hex(json!({"__cbor_undefined__": true}));
+        assert_eq!(hex.unwrap(), "f7");
+        let hex = canonical_hex(json!({"__cbor_simple__": 16}));
+        assert_eq!(hex.unwrap(), "f0");
+        let hex = canonical_hex(json!({"__cbor_float__": 1.5}));
+        assert_eq!(hex.unwrap(), "f93e00");
+        let hex = canonical_hex(json!([{"__cbor_undefined__": true}]));
+        assert_eq!(hex.unwrap(), "81f7");
+    }
+
+    #[test]
+    fn canonical_rejects_indefinite_markers() {
+        let error = canonical_hex(json!({"__cbor_indefinite_array__": [1]}));
+        assert_eq!(error.unwrap_err()["error_code"], "cbor_encode");
+        let error = canonical_hex(json!({"__cbor_float_width__": 64, "value": 1.5}));
+        assert_eq!(error.unwrap_err()["error_code"], "cbor_encode");
+    }
+
+    #[test]
+    fn canonical_sorts_map_keys() {
+        let hex = canonical_hex(json!({"bb": 1, "a": {"z": 2, "c": 3}}));
+        assert_eq!(hex.unwrap(), "a26161a2616303617a0262626201");
+        let hex = canonical_hex(json!({"__cbor_int_map__": {"10": 1, "-1": 2, "2": 3}}));
+        assert_eq!(hex.unwrap(), "a302030a012002");
+        let hex = canonical_hex(json!({
+            "__cbor_map_entries__": [[{"__cbor_undefined__": true}, 1], [0, 2]]
+        }));
+        assert_eq!(hex.unwrap(), "a20002f701");
+    }
+
+    #[test]
+    fn canonical_rejects_wide_int_width() {
+        let value = json!({"__cbor_int__": 10, "__cbor_int_width__": 2});
+        let error = canonical_hex(value.clone()).unwrap_err();
+        assert_eq!(error["error_code"], "cbor_encode");
+        assert!(error["error"]
+            .as_str()
+            .unwrap()
+            .contains("__cbor_int_width__"));
+        let hex = encode_cbor_value(value, &EncodeOptions::default()).map(hex::encode);
+        assert_eq!(hex.unwrap(), "19000a");
+        let hex = canonical_hex(json!({"__cbor_int__": 500, "__cbor_int_width__": 2}));
+        assert_eq!(hex.unwrap(), "1901f4");
+    }
+
+    #[test]
+    fn validate_accepts_simple_values() {
+        for hex in ["f0", "f7", "f8ff", "82e0f7"] {
+            assert_eq!(validate_cbor(hex)["valid"], true, "{}", hex);
+        }
+        let result = validate_cbor("f0f0");
+        assert_eq!(result["valid"], false);
+        assert_eq!(result["trailing_bytes"], 1);
+        assert_eq!(validate_cbor("f818")["valid"], false);
+    }
+
+    #[test]
+    fn diagnostic_renders_simple_values() {
+        assert_eq!(diag("f7"), "undefined");
+        assert_eq!(diag("f6"), "null");
+        assert_eq!(diag("f0"), "simple(16)");
+        assert_eq!(diag("f8ff"), "simple(255)");
+        assert_eq!(diag("84f6f7e0f5"), "[null, undefined, simple(0), true]");
+        assert_eq!(diag("a1f7d818f0"), "{undefined: 24(simple(16))}");
+    }
+
+    #[test]
+    fn simple_values_survive_keys_compare_and_roundtrip() {
+        // {simple(16): 1, undefined: 2, "a": 3}
+        let keys = keys_cbor("a3f001f702616103");
+        assert_eq!(
+            keys["keys"],
+            json!([
+                {"key": "simple(16)", "type": "simple"},
+                {"key": "undefined", "type": "simple"},
+                "a"
+            ])
+        );
+
+        assert_eq!(compare_cbor("82f0f7", "82f0f7")["equal"], true);
+        let result = compare_cbor("82f0f7", "82f0f6");
+        assert_eq!(result["equal"], false);
+        assert_eq!(result["reason"], "root[1]: undefined != null");
+        assert_eq!(
+            compare_cbor("a1f001", "a1f601")["reason"],
+            "root[simple(16)]: key missing from b"
+        );
+
+        for hex in ["f0", "f7", "83f6f7f8ff", "a1f7d818f0"] {
+            let result = roundtrip_cbor(hex);
+            assert_eq!(result["stable"], true, "{}", hex);
+            assert_eq!(result["reencoded_hex"], hex);
+        }
+    }
+
+    #[test]
+    fn root_tag_is_split_only_for_tags() {
+        let decoded = decode_cbor_bytes(
+            &decode_hex("d818420102").unwrap(),
+            &DecodeOptions::default(),
+        );
+        assert_eq!(decoded["tag"], 24);
+        assert_eq!(decoded["result"], json!({"__cbor_bytes__": "0102"}));
+
+        // {"__cbor_tag__": 5, "__cbor_value__": 1} is a plain map, not tag 5
+        let hex = "a26c5f5f63626f725f7461675f5f056e5f5f63626f725f76616c75655f5f01";
+        let decoded = decode_cbor_bytes(&decode_hex(hex).unwrap(), &DecodeOptions::default());
+        assert!(decoded.get("tag").is_none());
+        assert_eq!(decoded["result"]["__cbor_tag__"], 5);
+
+        // Nested tags keep the marker form under the root one
+        let decoded = decode_cbor_bytes(
+            &decode_hex("d90100d9010001").unwrap(),
+            &DecodeOptions::default(),
+        );
+        assert_eq!(decoded["tag"], 256);
+        assert_eq!(
+            decoded["result"],
+            json!({"__cbor_tag__": 256, "__cbor_value__": 1})
+        );
+    }
+
+    #[test]
+    fn bignums_round_trip_past_u64() {
+        // 2^127 and -2^127 - 1, sixteen magnitude bytes each
+        let cases = [
+            (
+                "c25080000000000000000000000000000000",
+                "170141183460469231731687303715884105728",
+            ),
+            (
+                "c35080000000000000000000000000000000",
+                "-170141183460469231731687303715884105729",
+            ),
+        ];
+        for (hex, decimal) in cases {
+            let decoded = decode(json!({"hex": hex}));
+            assert_eq!(decoded["result"], json!({"__cbor_bignum__": decimal}));
+            let encoded = encode(json!({"value": {"__cbor_bignum__": decimal}}));
+            assert_eq!(encoded["hex"], hex);
+        }
+
+        // A real tag 23 beside one keeps its number
+        let diag = diagnostic_cbor("82d74100c35080000000000000000000000000000000");
+        assert_eq!(
+            diag["result"],
+            "[23(h'00'), 3(h'80000000000000000000000000000000')]"
+        );
+    }
+}
@@ -1,240 +1,460 @@
-use ciborium::value::Value;
 use serde_json::{json, Value as JsonValue};
-use std::io::{self, Read};
-
-/// Convert CBOR Value to JSON with special markers for type preservation
-fn cbor_to_json(value: Value) -> JsonValue {
-    match value {
-        Value::Integer(i) => {
-            // Convert to i128 for safe handling
-            let num = i128::from(i);
-            // Check if it fits in JavaScript safe integer range
-            if num > 9007199254740991 || num < -9007199254740991 {
-                json!(num.to_string())
-            } else {
-                json!(num)
-            }
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use taco_cbor::{
+    decode_cbor_bytes, decode_hex, diagnostic_cbor, encode_cbor_value, encode_diag, error_response,
+    roundtrip_cbor, DecodeOptions, EncodeOptions, ErrorCode,
+};
+
+/// Read the action's input from `--file <path>` when given, otherwise from stdin
+fn read_input(flags: &[String]) -> Result<String, JsonValue> {
+    let mut input = String::new();
+    match flags.iter().position(|flag| flag == "--file") {
+        Some(i) => {
+            let path = flags
+                .get(i + 1)
+                .ok_or_else(|| error_response(ErrorCode::Usage, "Missing path after --file"))?;
+            input = fs::read_to_string(path).map_err(|e| {
+                error_response(
+                    ErrorCode::ReadFailed,
+                    format!("Failed to read {}: {}", path, e),
+                )
+            })?;
         }
-        Value::Bytes(bytes) => {
-            // Use special marker for byte strings
-            json!({
-                "__cbor_bytes__": hex::encode(&bytes)
-            })
+        None => {
+            io::stdin().read_to_string(&mut input).map_err(|e| {
+                error_response(
+                    ErrorCode::ReadFailed,
+                    format!("Failed to read stdin: {}", e),
+                )
+            })?;
         }
-        Value::Float(f) => {
-            // Handle special float values
-            if f.is_nan() {
-                json!({"__cbor_float__": "NaN"})
-            } else if f.is_infinite() {
-                if f.is_sign_positive() {
-                    json!({"__cbor_float__": "Infinity"})
-                } else {
-                    json!({"__cbor_float__": "-Infinity"})
-                }
-            } else {
-                json!(f)
-            }
+    }
+    Ok(input)
+}
+
+/// Stack size of --jobs worker threads, matching the main thread's: converting a deeply
+/// nested item recurses well past the 2 MiB default for spawned threads
+const WORKER_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Run `work` on every input across `jobs` threads, passing the results to `emit` in input order
+///
+/// Workers take the next unclaimed index and send back `(index, result)`; results that finish
+/// early wait in a reordering buffer until every earlier one has been emitted.
+fn run_jobs<T, F>(
+    inputs: &[T],
+    jobs: usize,
+    work: F,
+    mut emit: impl FnMut(JsonValue) -> io::Result<()>,
+) -> io::Result<()>
+where
+    T: Sync,
+    F: Fn(&T) -> JsonValue + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, inputs.len().max(1)) {
+            let sender = sender.clone();
+            let (next, work) = (&next, &work);
+            thread::Builder::new()
+                .stack_size(WORKER_STACK_SIZE)
+                .spawn_scoped(scope, move || loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(input) = inputs.get(index) else {
+                        break;
+                    };
+                    // The receiver is gone once emit has failed, which ends the run
+                    if sender.send((index, work(input))).is_err() {
+                        break;
+                    }
+                })?;
         }
-        Value::Text(s) => json!(s),
-        Value::Bool(b) => json!(b),
-        Value::Null => json!(null),
-        Value::Array(arr) => {
-            let converted: Vec<JsonValue> = arr.into_iter().map(cbor_to_json).collect();
-            json!(converted)
+        drop(sender);
+
+        // Results arrive in completion order; each waits here until its predecessors are out
+        let mut pending = BTreeMap::new();
+        let mut expected = 0;
+        for (index, result) in receiver {
+            pending.insert(index, result);
+            while let Some(result) = pending.remove(&expected) {
+                emit(result)?;
+                expected += 1;
+            }
         }
-        Value::Map(map) => {
-            let mut result = serde_json::Map::new();
-            for (k, v) in map {
-                let key = match k {
-                    Value::Text(s) => s,
-                    Value::Integer(i) => i128::from(i).to_string(),
-                    Value::Bytes(b) => hex::encode(&b),
-                    _ => format!("{:?}", k),
+        Ok(())
+    })
+}
+
+/// Run an action on every file matching a glob pattern, one result per file tagged with its path
+///
+/// A file that cannot be read gets a read_failed entry rather than stopping the sweep.
+fn run_glob(action: &str, pattern: &str, jobs: usize) -> Result<Vec<JsonValue>, JsonValue> {
+    let paths: Vec<_> = glob::glob(pattern)
+        .map_err(|e| {
+            error_response(
+                ErrorCode::Usage,
+                format!("Invalid glob pattern {}: {}", pattern, e),
+            )
+        })?
+        .collect();
+
+    let work = |entry: &Result<PathBuf, glob::GlobError>| {
+        let (file, mut result) = match entry {
+            Ok(path) => {
+                let result = match fs::read_to_string(path) {
+                    Ok(input) => run_action(action, &input),
+                    Err(e) => error_response(
+                        ErrorCode::ReadFailed,
+                        format!("Failed to read {}: {}", path.display(), e),
+                    ),
                 };
-                result.insert(key, cbor_to_json(v));
+                (path.display().to_string(), result)
             }
-            json!(result)
+            Err(e) => (
+                e.path().display().to_string(),
+                error_response(ErrorCode::ReadFailed, e.to_string()),
+            ),
+        };
+        result["file"] = json!(file);
+        result
+    };
+
+    let mut results = Vec::with_capacity(paths.len());
+    run_jobs(&paths, jobs, work, |result| {
+        results.push(result);
+        Ok(())
+    })
+    .map_err(|e| {
+        error_response(
+            ErrorCode::ReadFailed,
+            format!("Failed to start workers: {}", e),
+        )
+    })?;
+    Ok(results)
+}
+
+/// Run an action on every non-blank line of `input`, writing one result per line (NDJSON)
+///
+/// A failing line doesn't stop the rest; the worst status of any line is returned.
+fn run_batch(action: &str, input: &str, jobs: usize, out: &mut impl Write) -> io::Result<u8> {
+    let lines: Vec<&str> = input
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    let mut status = 0;
+    run_jobs(
+        &lines,
+        jobs,
+        |line| run_action(action, line),
+        |result| {
+            status = status.max(exit_status(&result));
+            writeln!(out, "{}", result)
+        },
+    )?;
+    Ok(status)
+}
+
+/// Run one action on one input, producing the JSON result object printed for it
+fn run_action(action: &str, input: &str) -> JsonValue {
+    match action {
+        "decode" => {
+            let bytes = match decode_hex(input.trim()) {
+                Ok(b) => b,
+                Err(error) => return error,
+            };
+
+            // The same conversion, and so the same result, as the HTTP container's /decode
+            decode_output(decode_cbor_bytes(&bytes, &DecodeOptions::default()))
         }
-        Value::Tag(tag, inner) => {
-            // Preserve tag information
+        // The same rendering as the HTTP container's /diagnostic
+        "diagnostic" => diagnostic_cbor(input.trim()),
+        // The same results as the HTTP container's /roundtrip and /encode-diag
+        "roundtrip" => roundtrip_cbor(input.trim()),
+        "from-diag" => encode_diag(input.trim()),
+        "encode" => {
+            // Parse JSON
+            let json_value: JsonValue = match serde_json::from_str(input) {
+                Ok(v) => v,
+                Err(e) => {
+                    return error_response(ErrorCode::InvalidJson, format!("Invalid JSON: {}", e))
+                }
+            };
+
+            // Encode CBOR exactly as the HTTP container's /encode does
+            let bytes = match encode_cbor_value(json_value, &EncodeOptions::default()) {
+                Ok(bytes) => bytes,
+                Err(error) => return error,
+            };
+
+            // Convert to hex
             json!({
-                "__cbor_tag__": tag,
-                "__cbor_value__": cbor_to_json(*inner)
+                "success": true,
+                "result": hex::encode(&bytes)
             })
         }
-        _ => json!(null),
+        _ => error_response(
+            ErrorCode::UnknownAction,
+            format!("Unknown action: {}", action),
+        ),
     }
 }
 
-/// Convert JSON to CBOR Value
-fn json_to_cbor(value: JsonValue) -> Value {
-    match value {
-        JsonValue::Null => Value::Null,
-        JsonValue::Bool(b) => Value::Bool(b),
-        JsonValue::Number(n) => {
-            if let Some(i) = n.as_i64() {
-                Value::Integer(i.into())
-            } else if let Some(f) = n.as_f64() {
-                Value::Float(f)
-            } else {
-                Value::Null
-            }
-        }
-        JsonValue::String(s) => {
-            // Check if it's a large integer string
-            if let Ok(i) = s.parse::<i64>() {
-                Value::Integer(i.into())
-            } else {
-                // For very large integers, keep as text (will be handled by test comparison)
-                Value::Text(s)
-            }
-        }
-        JsonValue::Array(arr) => {
-            let converted: Vec<Value> = arr.into_iter().map(json_to_cbor).collect();
-            Value::Array(converted)
-        }
-        JsonValue::Object(obj) => {
-            // Check for special markers
-            if let Some(bytes_hex) = obj.get("__cbor_bytes__") {
-                if let Some(hex_str) = bytes_hex.as_str() {
-                    if let Ok(bytes) = hex::decode(hex_str) {
-                        return Value::Bytes(bytes);
-                    }
-                }
-            }
-
-            if let Some(float_str) = obj.get("__cbor_float__") {
-                if let Some(s) = float_str.as_str() {
-                    return match s {
-                        "NaN" => Value::Float(f64::NAN),
-                        "Infinity" => Value::Float(f64::INFINITY),
-                        "-Infinity" => Value::Float(f64::NEG_INFINITY),
-                        _ => Value::Null,
-                    };
-                }
-            }
+/// Reduce a container /decode response to the CLI's `{"success", "result"}` output
+///
+/// The container adds timings, sizes and a hash, and lifts a root tag out of the result;
+/// the CLI prints only the converted value, with a root tag still wrapped around it.
+fn decode_output(response: JsonValue) -> JsonValue {
+    if response["success"] == false {
+        return json!({
+            "success": false,
+            "error": response["error"],
+            "error_code": response["error_code"]
+        });
+    }
+    let result = match response.get("tag") {
+        Some(tag) => json!({"__cbor_tag__": tag, "__cbor_value__": response["result"]}),
+        None => response["result"].clone(),
+    };
+    json!({
+        "success": true,
+        "result": result
+    })
+}
 
-            if let (Some(tag), Some(inner)) = (obj.get("__cbor_tag__"), obj.get("__cbor_value__")) {
-                if let Some(tag_num) = tag.as_u64() {
-                    return Value::Tag(tag_num, Box::new(json_to_cbor(inner.clone())));
-                }
-            }
+/// Process exit status for a printed result: 0 on success, 2 for usage errors, 1 otherwise
+fn exit_status(result: &JsonValue) -> u8 {
+    if result["success"] != false {
+        return 0;
+    }
+    match result["error_code"].as_str() {
+        Some(code) if code == ErrorCode::Usage.as_str() => 2,
+        Some(code) if code == ErrorCode::UnknownAction.as_str() => 2,
+        _ => 1,
+    }
+}
 
-            // Regular map
-            let mut map = Vec::new();
-            for (k, v) in obj {
-                map.push((Value::Text(k), json_to_cbor(v)));
-            }
-            Value::Map(map)
-        }
+/// Print a single result, indented when `pretty`, and exit with its status
+fn finish(result: JsonValue, pretty: bool) -> io::Result<ExitCode> {
+    if pretty {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        println!("{}", result);
     }
+    Ok(ExitCode::from(exit_status(&result)))
 }
 
-fn main() -> io::Result<()> {
+fn main() -> io::Result<ExitCode> {
     let args: Vec<String> = std::env::args().collect();
+    let pretty = args.iter().skip(2).any(|flag| flag == "--pretty");
 
     if args.len() < 2 {
-        let error = json!({
-            "success": false,
-            "error": "Usage: cbor_bridge <encode|decode>"
-        });
-        println!("{}", error);
-        return Ok(());
+        return finish(
+            error_response(
+                ErrorCode::Usage,
+                "Usage: cbor_bridge <encode|decode|diagnostic|roundtrip|from-diag> [--file <path> | --glob <pattern>] [--batch] [--jobs N] [--pretty]",
+            ),
+            pretty,
+        );
     }
 
     let action = &args[1];
+    let flags = &args[2..];
 
-    match action.as_str() {
-        "decode" => {
-            // Read hex string from stdin
-            let mut hex_input = String::new();
-            io::stdin().read_to_string(&mut hex_input)?;
-            let hex_input = hex_input.trim();
+    if !matches!(
+        action.as_str(),
+        "encode" | "decode" | "diagnostic" | "roundtrip" | "from-diag"
+    ) {
+        return finish(run_action(action, ""), pretty);
+    }
 
-            // Decode hex to bytes
-            let bytes = match hex::decode(hex_input) {
-                Ok(b) => b,
-                Err(e) => {
-                    let error = json!({
-                        "success": false,
-                        "error": format!("Invalid hex: {}", e)
-                    });
-                    println!("{}", error);
-                    return Ok(());
-                }
-            };
+    let jobs = match flags.iter().position(|flag| flag == "--jobs") {
+        Some(i) => match flags.get(i + 1).and_then(|n| n.parse::<usize>().ok()) {
+            Some(jobs) if jobs > 0 => jobs,
+            _ => {
+                return finish(
+                    error_response(ErrorCode::Usage, "--jobs needs a positive thread count"),
+                    pretty,
+                )
+            }
+        },
+        None => 1,
+    };
 
-            // Parse CBOR
-            let value: Value = match ciborium::from_reader(&bytes[..]) {
-                Ok(v) => v,
-                Err(e) => {
-                    let error = json!({
-                        "success": false,
-                        "error": format!("CBOR parse error: {}", e)
-                    });
-                    println!("{}", error);
-                    return Ok(());
-                }
-            };
+    if let Some(i) = flags.iter().position(|flag| flag == "--glob") {
+        // One combined array of per-file results; the worst file's status is the exit status
+        let Some(pattern) = flags.get(i + 1) else {
+            return finish(
+                error_response(ErrorCode::Usage, "Missing pattern after --glob"),
+                pretty,
+            );
+        };
+        let results = match run_glob(action, pattern, jobs) {
+            Ok(results) => results,
+            Err(error) => return finish(error, pretty),
+        };
+        let status = results.iter().map(exit_status).max().unwrap_or(0);
+        let report = JsonValue::Array(results);
+        if pretty {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            println!("{}", report);
+        }
+        return Ok(ExitCode::from(status));
+    }
 
-            // Convert to JSON
-            let json_value = cbor_to_json(value);
-            let result = json!({
-                "success": true,
-                "result": json_value
-            });
+    // Read hex string or JSON from --file or stdin
+    let input = match read_input(flags) {
+        Ok(input) => input,
+        Err(error) => return finish(error, pretty),
+    };
+
+    if flags.iter().any(|flag| flag == "--batch") {
+        // --pretty is ignored here since NDJSON needs each result on a single line
+        let status = run_batch(action, &input, jobs, &mut io::stdout().lock())?;
+        Ok(ExitCode::from(status))
+    } else {
+        finish(run_action(action, &input), pretty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            println!("{}", result);
+    /// Results with their timings removed, which are the only part that differs between runs
+    fn without_timings(output: &str) -> Vec<JsonValue> {
+        output
+            .lines()
+            .map(|line| {
+                let mut result: JsonValue = serde_json::from_str(line).unwrap();
+                let fields = result.as_object_mut().unwrap();
+                fields.retain(|key, _| !key.ends_with("_ms"));
+                result
+            })
+            .collect()
+    }
+
+    #[test]
+    fn from_diag_compiles_appendix_a_examples() {
+        for (diag, hex) in [
+            ("[1, [2, 3], [4, 5]]", "8301820203820405"),
+            (r#"{"a": 1, "b": [2, 3]}"#, "a26161016162820203"),
+            ("h'01020304'", "4401020304"),
+            ("-1000", "3903e7"),
+            ("1.5", "f93e00"),
+            ("Infinity", "f97c00"),
+            ("24(h'6449455446')", "d818456449455446"),
+        ] {
+            assert_eq!(run_action("from-diag", diag)["hex"], hex, "{}", diag);
         }
-        "encode" => {
-            // Read JSON from stdin
-            let mut json_input = String::new();
-            io::stdin().read_to_string(&mut json_input)?;
 
-            // Parse JSON
-            let json_value: JsonValue = match serde_json::from_str(&json_input) {
-                Ok(v) => v,
-                Err(e) => {
-                    let error = json!({
-                        "success": false,
-                        "error": format!("Invalid JSON: {}", e)
-                    });
-                    println!("{}", error);
-                    return Ok(());
-                }
-            };
+        // Errors point at the offending line and column of a multi-line input
+        let error = run_action("from-diag", "[1,\n  2,\n  @]");
+        assert_eq!(error["error_code"], "invalid_diagnostic");
+        assert!(error["error"]
+            .as_str()
+            .unwrap()
+            .ends_with("at line 3, column 3"));
+    }
 
-            // Convert to CBOR
-            let cbor_value = json_to_cbor(json_value);
-
-            // Encode CBOR
-            let mut bytes = Vec::new();
-            if let Err(e) = ciborium::into_writer(&cbor_value, &mut bytes) {
-                let error = json!({
-                    "success": false,
-                    "error": format!("CBOR encode error: {}", e)
-                });
-                println!("{}", error);
-                return Ok(());
-            }
+    #[test]
+    fn glob_jobs_keep_path_order() {
+        let dir = std::env::temp_dir().join(format!("cbor_bridge_glob_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for i in 0..50 {
+            let hex = format!("9a{:08x}{}", 3000 - i * 50, "01".repeat(3000 - i * 50));
+            fs::write(dir.join(format!("{:03}.hex", i)), hex).unwrap();
+        }
+        let pattern = format!("{}/*.hex", dir.display());
 
-            // Convert to hex
-            let hex_output = hex::encode(&bytes);
-            let result = json!({
-                "success": true,
-                "result": hex_output
-            });
+        let render = |results: Vec<JsonValue>| {
+            let lines: Vec<String> = results.iter().map(|r| r.to_string()).collect();
+            without_timings(&lines.join("\n"))
+        };
+        let serial = render(run_glob("decode", &pattern, 1).unwrap());
+        let parallel = render(run_glob("decode", &pattern, 6).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
 
-            println!("{}", result);
+        assert_eq!(serial.len(), 50);
+        assert!(serial[0]["file"].as_str().unwrap().ends_with("000.hex"));
+        assert_eq!(serial, parallel);
+    }
+
+    #[test]
+    fn hex_input_is_read_like_the_container() {
+        for input in ["0x1bffffffffffffffff", "1B FF FF FF\nFF FF FF FF FF"] {
+            assert_eq!(
+                run_action("decode", input)["result"],
+                "18446744073709551615"
+            );
         }
-        _ => {
-            let error = json!({
-                "success": false,
-                "error": format!("Unknown action: {}", action)
-            });
-            println!("{}", error);
+        assert_eq!(run_action("decode", "0xf")["error_code"], "invalid_hex");
+    }
+
+    #[test]
+    fn decode_prints_only_success_and_result() {
+        assert_eq!(
+            run_action("decode", "1864"),
+            json!({"success": true, "result": 100})
+        );
+        // A root tag stays wrapped around its content
+        assert_eq!(
+            run_action("decode", "d86401"),
+            json!({"success": true, "result": {"__cbor_tag__": 100, "__cbor_value__": 1}})
+        );
+        let error = run_action("decode", "18");
+        let fields: Vec<&String> = error.as_object().unwrap().keys().collect();
+        assert_eq!(fields, ["error", "error_code", "success"]);
+        assert_eq!(error["success"], false);
+    }
+
+    #[test]
+    fn integer_strings_match_the_container() {
+        for string in [
+            "9223372036854775808",
+            "18446744073709551615",
+            "-18446744073709551616",
+            "18446744073709551616",
+        ] {
+            let input = json!(string).to_string();
+            let container = encode_cbor_value(json!(string), &EncodeOptions::default()).unwrap();
+            assert_eq!(
+                run_action("encode", &input)["result"],
+                hex::encode(container)
+            );
         }
     }
 
-    Ok(())
+    #[test]
+    fn batch_jobs_keep_input_order() {
+        // Mixed sizes so later lines can finish before earlier ones, with a failure in between
+        let input: String = (0..200)
+            .map(|i| match i % 3 {
+                0 => format!("9a{:08x}{}\n", 5000 - i, "01".repeat(5000 - i)),
+                1 => format!("{:02x}\n", i % 24),
+                _ => "zz\n".to_string(),
+            })
+            .collect();
+
+        let mut serial = Vec::new();
+        let serial_status = run_batch("decode", &input, 1, &mut serial).unwrap();
+        let mut parallel = Vec::new();
+        let parallel_status = run_batch("decode", &input, 8, &mut parallel).unwrap();
+
+        assert_eq!(serial_status, 1);
+        assert_eq!(parallel_status, serial_status);
+        let serial = without_timings(&String::from_utf8(serial).unwrap());
+        assert_eq!(serial.len(), 200);
+        assert_eq!(
+            serial,
+            without_timings(&String::from_utf8(parallel).unwrap())
+        );
+    }
 }